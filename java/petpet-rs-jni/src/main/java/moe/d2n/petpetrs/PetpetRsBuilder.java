@@ -12,10 +12,18 @@ public class PetpetRsBuilder implements AutoCloseable {
     protected static native long createBuilder(String template, String path) throws RuntimeException;
     protected static native void closeBuilder(long pointer);
 
+    /**
+     * @param options JSON, same fields as {@code requester} in server config
+     */
+    public static native void setRequesterOptions(String options) throws RuntimeException;
+
     // TODO: Map Exception
-    protected static native byte[] builderBuildByString(long pointer, String data) throws RuntimeException;
-    protected static native byte[] builderBuildByObjects(
-            long pointer, AvatarUrlsData avatarData, TextData textData
+    protected static native Result builderBuildByString(long pointer, String data) throws RuntimeException;
+    /**
+     * @param format {@link EncodeFormat} name, null to decide by frame count
+     */
+    protected static native Result builderBuildByObjects(
+            long pointer, AvatarUrlsData avatarData, TextData textData, String format
     ) throws RuntimeException;
 
     public PetpetRsBuilder(String template, String path) {
@@ -24,8 +32,7 @@ public class PetpetRsBuilder implements AutoCloseable {
 
     public Result build(String data) throws RuntimeException {
         if (this.closed) throw new IllegalStateException("Builder Already closed");
-        byte[] bytes = builderBuildByString(this.pointer, data);
-        return new Result(bytes, EncodeFormat.PNG);
+        return builderBuildByString(this.pointer, data);
     }
 
     /**
@@ -33,9 +40,18 @@ public class PetpetRsBuilder implements AutoCloseable {
      * @param textData Not Null
      */
     public Result build(AvatarUrlsData avatarData, TextData textData) {
+        return build(avatarData, textData, null);
+    }
+
+    /**
+     * @param avatarData Not Null
+     * @param textData Not Null
+     * @param format null to decide by frame count
+     */
+    public Result build(AvatarUrlsData avatarData, TextData textData, EncodeFormat format) {
+        if (this.closed) throw new IllegalStateException("Builder Already closed");
         if (avatarData == null || textData == null) throw new IllegalArgumentException("avatarData and textData must not null");
-        byte[] bytes = builderBuildByObjects(this.pointer, avatarData, textData);
-        return new Result(bytes, EncodeFormat.PNG);
+        return builderBuildByObjects(this.pointer, avatarData, textData, format == null ? null : format.name());
     }
 
     @Override
@@ -126,7 +142,7 @@ public class PetpetRsBuilder implements AutoCloseable {
     }
 
     public enum EncodeFormat {
-        PNG, GIF
+        PNG, GIF, WEBP, APNG
     }
 
     public static class Result {
@@ -137,5 +153,12 @@ public class PetpetRsBuilder implements AutoCloseable {
             this.bytes = bytes;
             this.format = format;
         }
+
+        /**
+         * called from native code with the {@link EncodeFormat} ordinal
+         */
+        private Result(byte[] bytes, int format) {
+            this(bytes, EncodeFormat.values()[format]);
+        }
     }
 }
@@ -1,20 +1,33 @@
 import json
 import os
-from dataclasses import asdict
-from dataclasses import dataclass, field, asdict
+from dataclasses import dataclass, field, fields, is_dataclass
 from enum import Enum, auto
 from typing import Optional, List, Dict, Tuple
 
 import petpet_rs.petpet
 
 
+@dataclass
+class AvatarDataBase64:
+    from_: Optional[str] = field(default=None, metadata={"key": "from"})
+    to: Optional[str] = None
+    bot: Optional[str] = None
+    group: Optional[str] = None
+    random: Optional[List[str]] = field(default_factory=list)
+
+
 @dataclass
 class AvatarDataURL:
+    # url or "data:image/...;base64," uri
     from_: Optional[str] = field(default=None, metadata={"key": "from"})
     to: Optional[str] = None
     bot: Optional[str] = None
     group: Optional[str] = None
     random: Optional[List[str]] = field(default_factory=list)
+    # plain base64 images, take precedence over urls
+    base64: Optional[AvatarDataBase64] = None
+    # overrides template fallback, e.g. [{"AVATAR": "FROM"}, "PLACEHOLDER"]
+    fallback: Optional[List] = None
 
 
 @dataclass
@@ -29,11 +42,32 @@ class TextData:
 class PetpetData:
     avatar: AvatarDataURL = field(default_factory=AvatarDataURL)
     text: TextData = field(default_factory=TextData)
+    # "png" | "gif" | "webp" | "apng", None to decide by frame count
+    format: Optional[str] = None
+    # PNG / WebP quality, 0 - 100
+    quality: Optional[int] = None
+    # GIF quantization speed, 1 (best) - 30 (fastest)
+    speed: Optional[int] = None
+    # GIF with global palette and frame diff
+    optimize: Optional[bool] = None
+
+
+def _to_dict(obj):
+    # like dataclasses.asdict, but honors field(metadata={"key": ...}) renames
+    if is_dataclass(obj):
+        return {f.metadata.get("key", f.name): _to_dict(getattr(obj, f.name)) for f in fields(obj)}
+    if isinstance(obj, list):
+        return [_to_dict(v) for v in obj]
+    if isinstance(obj, dict):
+        return {k: _to_dict(v) for k, v in obj.items()}
+    return obj
 
 
 class ResultFormat(Enum):
     PNG = auto()
     GIF = auto()
+    WEBP = auto()
+    APNG = auto()
 
     @staticmethod
     def from_raw(raw: petpet_rs.petpet.PyOutputFormat):
@@ -41,12 +75,28 @@ class ResultFormat(Enum):
             return ResultFormat.PNG
         elif raw == petpet_rs.petpet.PyOutputFormat.GIF:
             return ResultFormat.GIF
+        elif raw == petpet_rs.petpet.PyOutputFormat.WEBP:
+            return ResultFormat.WEBP
+        elif raw == petpet_rs.petpet.PyOutputFormat.APNG:
+            return ResultFormat.APNG
 
     def __str__(self):
         if self == ResultFormat.PNG:
             return "png"
         elif self == ResultFormat.GIF:
             return "gif"
+        elif self == ResultFormat.WEBP:
+            return "webp"
+        elif self == ResultFormat.APNG:
+            return "apng"
+
+
+def set_requester_options(options: dict):
+    """
+    same fields as `requester` in server config,
+    e.g. {"userAgent": "...", "proxy": "socks5://127.0.0.1:1080", "maxResponseSize": 16777216}
+    """
+    petpet_rs.petpet.set_requester_options(options)
 
 
 class PetpetBuilder:
@@ -63,7 +113,7 @@ class PetpetBuilder:
         return PetpetBuilder(template, path)
 
     async def build(self, data: PetpetData) -> Tuple[bytes, ResultFormat]:
-        blob, format_type = await self.__builder.build(asdict(data))
+        blob, format_type = await self.__builder.build(_to_dict(data))
         return blob, ResultFormat.from_raw(format_type)
 
 
@@ -1,14 +1,19 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use futures::future::{BoxFuture, join_all};
 use rand::Rng;
-use skia_safe::{Image, Matrix};
+use skia_safe::{Color, Image, Matrix};
 
-use crate::core::builder::pos_builder::{compile_pos, CompiledPos};
+use crate::core::builder::keyframe_builder::expand_keyframes;
+use crate::core::builder::pos_builder::{compile_float, compile_pos, CompiledFloat, CompiledNumberPosDimension, CompiledPos};
 use crate::core::errors::Error;
 use crate::core::errors::Error::{MissingDataError, TemplateError};
+use crate::core::loader::color_util::parse_color;
+use crate::core::loader::frame_limit::limit_frames;
+use crate::core::loader::placeholder::{placeholder_image, solid_image};
 use crate::core::model::avatar_model::AvatarModel;
-use crate::core::template::avatar_template::{AvatarCropType, AvatarPosType, AvatarStyle, AvatarTemplate, AvatarType, CropPos, PosDimension};
+use crate::core::template::avatar_template::{AvatarCropType, AvatarFallback, AvatarFrameLimit, AvatarPosType, AvatarStyle, AvatarTemplate, AvatarType, CropPos, FloatItem, PosDimension};
 use crate::core::template::filter_template::AvatarFilter;
 
 pub static FROM: usize = 0b00001;
@@ -30,6 +35,16 @@ pub fn by_type(t: &AvatarType) -> usize {
 pub struct AvatarBuiltTemplate {
     pub raw: AvatarTemplate,
     pub pos: CompiledPos,
+    /// degrees per frame, numbers are normalized to 0..360
+    pub angle: Vec<CompiledFloat>,
+    /// per frame
+    pub opacity: Vec<f32>,
+    /// per frame
+    pub scale: Vec<f32>,
+    /// longest of the per-frame pos, angle, opacity and scale lists
+    pub frame_length: usize,
+    /// x1, y1, x2, y2
+    pub crop: Option<[CompiledFloat; 4]>,
     pub max_length: usize,
     pub matrix: Matrix,
 }
@@ -38,7 +53,8 @@ pub struct AvatarBuilder {
     built_template: AvatarBuiltTemplate,
 }
 
-pub type AvatarFrames = (Arc<Vec<Image>>, u16);
+/// frames and their durations in ms, a static image has one frame of duration 0
+pub type AvatarFrames = (Arc<Vec<Image>>, Vec<u32>);
 pub type AvatarDataItem<'a> = BoxFuture<'a, Result<AvatarFrames, Error>>;
 
 pub struct AvatarData<'a> {
@@ -47,10 +63,25 @@ pub struct AvatarData<'a> {
     pub bot: Option<AvatarDataItem<'a>>,
     pub group: Option<AvatarDataItem<'a>>,
     pub random: Vec<AvatarDataItem<'a>>,
+    /// overrides the template fallback
+    pub fallback: Option<Vec<AvatarFallback>>,
 }
 
 impl AvatarBuilder {
     pub fn new<'a>(mut template: AvatarTemplate, background_length: usize) -> Result<AvatarBuilder, Error> {
+        let mut keyframe_angle = None;
+        let mut keyframe_opacity = None;
+        let mut keyframe_scale = None;
+        if let Some(keyframes) = &template.keyframes {
+            let expanded = expand_keyframes(keyframes, &template.pos_type, background_length)?;
+            if let Some(pos) = expanded.pos {
+                template.pos = pos;
+            }
+            keyframe_angle = expanded.angle;
+            keyframe_opacity = expanded.opacity;
+            keyframe_scale = expanded.scale;
+        }
+
         let pos: PosDimension = match &template.pos_type {
             AvatarPosType::ZOOM => match &template.pos {
                 PosDimension::P1D(pos) => PosDimension::P2D(vec![pos.clone()]),
@@ -65,16 +96,47 @@ impl AvatarBuilder {
         };
 
         let pos = compile_pos(pos)?;
-        template.angle %= 360.0;
+        for angle in &mut template.angle {
+            if let FloatItem::Num(angle) = angle {
+                *angle %= 360.0;
+            }
+        }
+        let angle = match keyframe_angle {
+            Some(angles) => angles.into_iter().map(|a| CompiledFloat::Num(a % 360.0)).collect(),
+            None => template.angle.iter().map(compile_float).collect::<Result<Vec<_>, Error>>()?,
+        };
+        let opacity = keyframe_opacity.unwrap_or_else(|| template.opacity.clone());
+        let scale = keyframe_scale.unwrap_or_else(|| template.scale.clone());
+        if angle.is_empty() || opacity.is_empty() || scale.is_empty() {
+            return Err(TemplateError("angle, opacity and scale lists must not be empty".to_string()));
+        }
+        let frame_length = [
+            match &pos.0 {
+                CompiledNumberPosDimension::P2D(p2d) => p2d.len(),
+                CompiledNumberPosDimension::P3D(p3d) => p3d.len(),
+            },
+            angle.len(),
+            opacity.len(),
+            scale.len(),
+        ].into_iter().fold(1, usize::max);
 
         template.crop = match &template.crop_type {
             AvatarCropType::NONE => None,
             _ => {
                 if let CropPos::WH(wh) = template.crop.as_ref().ok_or(TemplateError("Can not find crop pos".to_string()))? {
-                    Some(CropPos::XYXY((0.0, 0.0, wh.0, wh.1)))
+                    Some(CropPos::XYXY((FloatItem::Num(0.0), FloatItem::Num(0.0), wh.0.clone(), wh.1.clone())))
                 } else { template.crop }
             }
         };
+        let crop = match &template.crop {
+            Some(CropPos::XYXY((x1, y1, x2, y2))) => Some([
+                compile_float(x1)?,
+                compile_float(y1)?,
+                compile_float(x2)?,
+                compile_float(y2)?,
+            ]),
+            _ => None,
+        };
 
         for style in &template.style {
             match style {
@@ -96,6 +158,11 @@ impl AvatarBuilder {
                 raw: template,
                 max_length,
                 pos,
+                angle,
+                opacity,
+                scale,
+                frame_length,
+                crop,
                 matrix,
             },
         })
@@ -114,18 +181,25 @@ impl AvatarBuilder {
         Matrix::default()
     }
 
-    pub fn build(&self, frames: AvatarFrames) -> Result<AvatarModel, Error> {
-        AvatarModel::new(&self.built_template, frames)
+    pub fn build(&self, frames: AvatarFrames, frame_ms: u32) -> Result<AvatarModel, Error> {
+        AvatarModel::new(&self.built_template, frames, frame_ms)
     }
 }
 
 pub struct AvatarBuilderList {
     types: usize,
     pub builders: Vec<(usize, bool, AvatarBuilder)>,
+    fallback: Vec<AvatarFallback>,
+    frame_limit: AvatarFrameLimit,
 }
 
 impl AvatarBuilderList {
-    pub fn new<'a>(templates: Vec<AvatarTemplate>, background_length: usize) -> Result<AvatarBuilderList, Error> {
+    pub fn new<'a>(
+        templates: Vec<AvatarTemplate>,
+        background_length: usize,
+        fallback: Vec<AvatarFallback>,
+        frame_limit: AvatarFrameLimit,
+    ) -> Result<AvatarBuilderList, Error> {
         let mut types = 0;
         let mut items = Vec::with_capacity(templates.len());
         for avatar in templates {
@@ -139,10 +213,20 @@ impl AvatarBuilderList {
         Ok(AvatarBuilderList {
             types,
             builders: items,
+            fallback,
+            frame_limit,
         })
     }
 
-    pub async fn build<'a>(&'a self, data: AvatarData<'a>) -> Result<Vec<AvatarModel<'a>>, Error> {
+    /// `frame_ms` is the output frame duration, `None` to follow the avatars
+    /// (the fastest animated one, `default_ms` if none is animated).
+    /// returns the frame duration avatars were retimed against
+    pub async fn build<'a>(
+        &'a self,
+        data: AvatarData<'a>,
+        frame_ms: Option<u32>,
+        default_ms: u32,
+    ) -> Result<(Vec<AvatarModel<'a>>, u32), Error> {
         // if self.types & if data.from.is_none() { 0 } else { FROM } == 0
         //     && self.types & if data.to.is_none() { 0 } else { TO } == 0
         //     && self.types & if data.bot.is_none() { 0 } else { BOT } == 0
@@ -150,54 +234,114 @@ impl AvatarBuilderList {
         //     && self.types & if data.random.is_none() { 0 } else { RANDOM } == 0 {
         //     return Err(MissingDataError(""));
         // }
-        let (futures, types) = build_future(self.types, data)?;
-        let mut res = join_all(futures).await;
-        let mut avatars = Vec::with_capacity(res.len());
-        for i in 0..res.len() {
-            let t = types[i];
-            let (imgs, delay) = res.remove(0)?;
+        let fallback = data.fallback.clone().unwrap_or_else(|| self.fallback.clone());
+        let mut pending = pending_futures(data);
+        let types: Vec<usize> = [FROM, TO, GROUP, BOT, RANDOM].into_iter()
+            .filter(|t| self.types & t != 0)
+            .collect();
+        let results = join_all(types.iter().map(|t| {
+            let future = pending.remove(t);
+            let t = *t;
+            async move {
+                match future {
+                    Some(future) => future.await,
+                    None => Err(MissingDataError(format!("Missing {} data", type_name(t)))),
+                }
+            }
+        })).await;
+
+        let mut loaded: HashMap<usize, AvatarFrames> = HashMap::with_capacity(types.len());
+        let mut failed = Vec::new();
+        for (t, result) in types.iter().zip(results) {
+            match result {
+                Ok(frames) => { loaded.insert(*t, limit_frames(frames, &self.frame_limit)?); }
+                Err(err) => failed.push((*t, err)),
+            }
+        }
+        for (t, err) in failed {
+            let frames = resolve_fallback(err, &fallback, &loaded, &mut pending).await?;
+            loaded.insert(t, limit_frames(frames, &self.frame_limit)?);
+        }
+
+        let frame_ms = frame_ms.unwrap_or_else(|| loaded.values()
+            .filter(|(imgs, _)| imgs.len() > 1)
+            .map(|(imgs, durations)| durations.iter().sum::<u32>() / imgs.len() as u32)
+            .filter(|ms| *ms > 0)
+            .min()
+            .unwrap_or(default_ms));
+        let frame_ms = u32::max(10, frame_ms);
+
+        let mut avatars = Vec::with_capacity(self.builders.len());
+        for t in types {
+            let (imgs, durations) = &loaded[&t];
             for (_, _, builder) in &self.builders {
                 if by_type(&builder.built_template.raw._type) != t {
                     continue;
                 }
                 avatars.push(builder.build(
-                    (Arc::clone(&imgs), delay)
+                    (Arc::clone(imgs), durations.clone()),
+                    frame_ms,
                 )?);
             }
         }
 
-        Ok(avatars)
+        Ok((avatars, frame_ms))
     }
 }
 
-fn build_future(types: usize, data: AvatarData)
-                -> Result<(Vec<BoxFuture<Result<AvatarFrames, Error>>>, Vec<usize>), Error> {
-    let mut future_vec = Vec::with_capacity(5);
-    let mut extra_vec: Vec<usize> = Vec::with_capacity(5);
-    if types & FROM != 0 {
-        future_vec.push(data.from.ok_or_else(|| MissingDataError("Missing FROM data".to_string()))?);
-        extra_vec.push(FROM);
-    }
-    if types & TO != 0 {
-        future_vec.push(data.to.ok_or_else(|| MissingDataError("Missing TO data".to_string()))?);
-        extra_vec.push(TO);
-    }
-    if types & GROUP != 0 {
-        future_vec.push(data.group.ok_or_else(|| MissingDataError("Missing GROUP data".to_string()))?);
-        extra_vec.push(GROUP);
+/// unused futures are kept for `AvatarFallback::AVATAR`
+fn pending_futures(data: AvatarData) -> HashMap<usize, AvatarDataItem> {
+    let mut map = HashMap::with_capacity(5);
+    for (t, future) in [(FROM, data.from), (TO, data.to), (GROUP, data.group), (BOT, data.bot)] {
+        if let Some(future) = future {
+            map.insert(t, future);
+        }
     }
-    if types & BOT != 0 {
-        future_vec.push(data.bot.ok_or_else(|| MissingDataError("Missing BOT data".to_string()))?);
-        extra_vec.push(BOT);
+    let mut random = data.random;
+    if !random.is_empty() {
+        let index = rand::thread_rng().gen_range(0..random.len());
+        map.insert(RANDOM, random.remove(index));
     }
-    if types & RANDOM != 0 {
-        let mut vec = data.random;
-        if vec.is_empty() {
-            return Err(MissingDataError("Missing RANDOM data".to_string()));
+    map
+}
+
+/// returns the original error if no fallback can be used
+async fn resolve_fallback<'a>(
+    err: Error,
+    fallback: &[AvatarFallback],
+    loaded: &HashMap<usize, AvatarFrames>,
+    pending: &mut HashMap<usize, AvatarDataItem<'a>>,
+) -> Result<AvatarFrames, Error> {
+    for item in fallback {
+        match item {
+            AvatarFallback::PLACEHOLDER => {
+                return Ok((Arc::new(vec![placeholder_image(Color::GRAY)]), vec![0]));
+            }
+            AvatarFallback::COLOR(color) => {
+                return Ok((Arc::new(vec![solid_image(parse_color(color)?)]), vec![0]));
+            }
+            AvatarFallback::AVATAR(avatar_type) => {
+                let t = by_type(avatar_type);
+                if let Some(frames) = loaded.get(&t) {
+                    return Ok(frames.clone());
+                }
+                if let Some(future) = pending.remove(&t) {
+                    if let Ok(frames) = future.await {
+                        return Ok(frames);
+                    }
+                }
+            }
         }
-        let index = rand::thread_rng().gen_range(0..vec.len());
-        future_vec.push(vec.remove(index));
-        extra_vec.push(RANDOM);
     }
-    Ok((future_vec, extra_vec))
+    Err(err)
+}
+
+fn type_name(t: usize) -> &'static str {
+    match t {
+        t if t == FROM => "FROM",
+        t if t == TO => "TO",
+        t if t == GROUP => "GROUP",
+        t if t == BOT => "BOT",
+        _ => "RANDOM",
+    }
 }
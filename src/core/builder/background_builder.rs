@@ -1,13 +1,14 @@
 use alloc::borrow::Cow;
+use std::sync::Arc;
 
 use rayon::prelude::*;
 use skia_safe::{AlphaType, Color, ColorType, Image, ImageInfo, Surface};
 
-use crate::core::builder::pos_builder::{compile_size, CompiledSize, eval_background_size};
+use crate::core::builder::pos_builder::{compile_size, CompiledSize, eval_background_size, ExprContext};
 use crate::core::errors::Error;
 use crate::core::errors::Error::TemplateError;
 use crate::core::loader::color_util::parse_color;
-use crate::core::loader::image_loader::{image_count, load_cached_background};
+use crate::core::loader::image_loader::{image_count, image_size, load_cached_background};
 use crate::core::template::background_template::BackgroundTemplate;
 
 pub struct BackgroundBuilder {
@@ -16,8 +17,6 @@ pub struct BackgroundBuilder {
     pub path: Option<String>,
 }
 
-static EMPTY_VEC: Vec<Image> = vec![];
-
 impl BackgroundBuilder {
     pub fn new(
         template: Option<BackgroundTemplate>,
@@ -48,20 +47,26 @@ impl BackgroundBuilder {
         }
     }
 
-    pub fn create_background(&self, avatar_sizes: Vec<OriginSize>, text_sizes: Vec<OriginSize>)
+    /// canvas size if it does not depend on avatar or text size
+    pub fn static_size(&self) -> Option<OriginSize> {
+        match &self.info {
+            Some((size, expr_vec)) => if expr_vec.is_empty() { Some(*size) } else { None },
+            None => image_size(self.path.as_ref()?),
+        }
+    }
+
+    pub fn create_background(&self, expr_context: &ExprContext)
         -> Result<(Surface, Cow<Vec<Image>>), Error>
     {
         let file_images = match &self.path {
             Some(path) => {
                 load_cached_background(path)?
             }
-            None => &EMPTY_VEC
+            None => Arc::new(Vec::new())
         };
         // TODO: lazy eval
         let size = match &self.info {
-            Some((size, _)) => eval_background_size(
-                size, avatar_sizes, text_sizes
-            )?,
+            Some((size, _)) => eval_background_size(size, expr_context)?,
             None => (file_images[0].width(), file_images[0].height())
         };
         let info = ImageInfo::new(
@@ -83,15 +88,24 @@ impl BackgroundBuilder {
                     }
                     return Ok((s, Cow::Owned(images)));
                 }
-                (s, Cow::Borrowed(file_images))
+                (s, Cow::Owned(file_images.to_vec()))
             }
             None => (
                 skia_safe::surfaces::raster(&info, 0, None).unwrap(),
-                Cow::Borrowed(file_images)
+                Cow::Owned(file_images.to_vec())
             )
         })
     }
 
+    /// frame count after `repeat_for_avatar_length`, known before the background is created
+    pub fn output_length(&self, avatar_length: usize) -> usize {
+        if self.length > 1 || avatar_length <= 1 {
+            self.length
+        } else {
+            avatar_length
+        }
+    }
+
     pub fn repeat_for_avatar_length(bgs: Cow<Vec<Image>>, avatar_length: usize) -> Cow<Vec<Image>>{
         if bgs.len() > 1 || avatar_length <= 1 {
             return bgs
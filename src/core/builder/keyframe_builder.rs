@@ -0,0 +1,199 @@
+use crate::core::builder::pos_builder::ease_in_out;
+use crate::core::errors::Error;
+use crate::core::errors::Error::TemplateError;
+use crate::core::template::avatar_template::{AvatarKeyframes, AvatarPosType, KeyframeEasing, PosDimension, PosItem};
+
+/// per-frame values of the properties set by at least one keyframe
+pub struct ExpandedKeyframes {
+    pub pos: Option<PosDimension>,
+    pub angle: Option<Vec<f32>>,
+    pub opacity: Option<Vec<f32>>,
+    pub scale: Option<Vec<f32>>,
+}
+
+/// keyframe index, values and easing towards the next keyframe
+type Track<'a> = Vec<(usize, Vec<f32>, &'a KeyframeEasing)>;
+
+pub fn expand_keyframes(
+    keyframes: &AvatarKeyframes,
+    pos_type: &AvatarPosType,
+    background_length: usize,
+) -> Result<ExpandedKeyframes, Error> {
+    let mut frames: Vec<_> = keyframes.frames.iter().collect();
+    frames.sort_by_key(|k| k.frame);
+    let last = frames.last().ok_or_else(|| TemplateError("keyframes must not be empty".to_string()))?;
+    let length = keyframes.length.unwrap_or(usize::max(last.frame + 1, background_length));
+
+    let mut pos_track: Track = Vec::new();
+    let mut angle_track: Track = Vec::new();
+    let mut opacity_track: Track = Vec::new();
+    let mut scale_track: Track = Vec::new();
+    for key in &frames {
+        let easing = key.easing.as_ref().unwrap_or(&keyframes.easing);
+        if let Some(pos) = &key.pos {
+            pos_track.push((key.frame, flatten_pos(pos, pos_type)?, easing));
+        }
+        if let Some(angle) = key.angle {
+            angle_track.push((key.frame, vec![angle], easing));
+        }
+        if let Some(opacity) = key.opacity {
+            opacity_track.push((key.frame, vec![opacity], easing));
+        }
+        if let Some(scale) = key.scale {
+            scale_track.push((key.frame, vec![scale], easing));
+        }
+    }
+
+    let pos = if pos_track.is_empty() {
+        None
+    } else {
+        let values: Vec<Vec<f32>> = (0..length).map(|i| sample(&pos_track, i)).collect();
+        Some(unflatten_pos(values, pos_type))
+    };
+    let scalar = |track: &Track| -> Option<Vec<f32>> {
+        if track.is_empty() {
+            None
+        } else {
+            Some((0..length).map(|i| sample(track, i)[0]).collect())
+        }
+    };
+    Ok(ExpandedKeyframes {
+        pos,
+        angle: scalar(&angle_track),
+        opacity: scalar(&opacity_track),
+        scale: scalar(&scale_track),
+    })
+}
+
+fn sample(track: &Track, frame: usize) -> Vec<f32> {
+    let next = track.iter().position(|(f, _, _)| *f > frame);
+    let (prev, next) = match next {
+        Some(0) => return track[0].1.clone(),
+        Some(next) => (&track[next - 1], &track[next]),
+        None => return track[track.len() - 1].1.clone(),
+    };
+    let t = (frame - prev.0) as f32 / (next.0 - prev.0) as f32;
+    let t = ease(prev.2, t);
+    prev.1.iter().zip(&next.1)
+        .map(|(a, b)| a + (b - a) * t)
+        .collect()
+}
+
+fn ease(easing: &KeyframeEasing, t: f32) -> f32 {
+    match easing {
+        KeyframeEasing::LINEAR => t,
+        KeyframeEasing::EASE_IN => t * t,
+        KeyframeEasing::EASE_OUT => 1.0 - (1.0 - t) * (1.0 - t),
+        KeyframeEasing::EASE_IN_OUT => ease_in_out(t as f64) as f32,
+        KeyframeEasing::STEP => 0.0,
+    }
+}
+
+fn flatten_pos(pos: &PosDimension, pos_type: &AvatarPosType) -> Result<Vec<f32>, Error> {
+    let items: Vec<&PosItem> = match (pos_type, pos) {
+        (AvatarPosType::ZOOM, PosDimension::P1D(xywh)) if xywh.len() == 4 => xywh.iter().collect(),
+        (AvatarPosType::DEFORM, PosDimension::P2D(points))
+        if points.len() == 5 && points.iter().all(|p| p.len() == 2) => points.iter().flatten().collect(),
+        _ => return Err(TemplateError(format!(
+            "keyframe pos must be [x, y, w, h] for ZOOM or five [x, y] for DEFORM ({:?})", pos
+        ))),
+    };
+    items.into_iter().map(|item| match item {
+        PosItem::Num(num) => Ok(*num as f32),
+        PosItem::Expr(expr) => Err(TemplateError(format!(
+            "keyframe pos can not be an expression ({})", expr
+        ))),
+    }).collect()
+}
+
+fn unflatten_pos(values: Vec<Vec<f32>>, pos_type: &AvatarPosType) -> PosDimension {
+    let to_items = |values: &[f32]| values.iter()
+        .map(|v| PosItem::Num(v.round() as i32))
+        .collect::<Vec<PosItem>>();
+    match pos_type {
+        AvatarPosType::ZOOM => PosDimension::P2D(
+            values.iter().map(|v| to_items(v)).collect()
+        ),
+        AvatarPosType::DEFORM => PosDimension::P3D(
+            values.iter().map(|v| v.chunks(2).map(&to_items).collect()).collect()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframes(json: &str) -> AvatarKeyframes {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn angles(json: &str, background_length: usize) -> Vec<f32> {
+        expand_keyframes(&keyframes(json), &AvatarPosType::ZOOM, background_length)
+            .unwrap().angle.unwrap()
+    }
+
+    #[test]
+    fn endpoints_and_midpoint() {
+        let angle = angles(r#"{"frames": [{"frame": 0, "angle": 0}, {"frame": 10, "angle": 100}]}"#, 1);
+        assert_eq!(angle.len(), 11);
+        assert_eq!(angle[0], 0.0);
+        assert_eq!(angle[5], 50.0);
+        assert_eq!(angle[10], 100.0);
+    }
+
+    #[test]
+    fn easing() {
+        let ease_in = angles(r#"{"easing": "EASE_IN", "frames": [{"frame": 0, "angle": 0}, {"frame": 10, "angle": 100}]}"#, 1);
+        assert_eq!(ease_in[5], 25.0);
+        // per keyframe easing overrides the default
+        let step = angles(r#"{"frames": [{"frame": 0, "angle": 0, "easing": "STEP"}, {"frame": 10, "angle": 100}]}"#, 1);
+        assert_eq!(step[9], 0.0);
+        assert_eq!(step[10], 100.0);
+    }
+
+    #[test]
+    fn clamped_outside_keyframes() {
+        let angle = angles(r#"{"length": 9, "frames": [{"frame": 6, "angle": 90}, {"frame": 2, "angle": 30}]}"#, 1);
+        assert_eq!(angle.len(), 9);
+        assert_eq!(&angle[..3], &[30.0, 30.0, 30.0]);
+        assert_eq!(angle[4], 60.0);
+        assert_eq!(&angle[6..], &[90.0, 90.0, 90.0]);
+    }
+
+    #[test]
+    fn single_keyframe() {
+        let expanded = expand_keyframes(
+            &keyframes(r#"{"frames": [{"frame": 3, "opacity": 0.5}]}"#),
+            &AvatarPosType::ZOOM,
+            6,
+        ).unwrap();
+        assert_eq!(expanded.opacity.unwrap(), vec![0.5; 6]);
+        assert!(expanded.pos.is_none());
+        assert!(expanded.angle.is_none());
+        assert!(expanded.scale.is_none());
+    }
+
+    #[test]
+    fn zoom_pos() {
+        let expanded = expand_keyframes(
+            &keyframes(r#"{"frames": [{"frame": 0, "pos": [0, 0, 10, 10]}, {"frame": 2, "pos": [10, 21, 30, 10]}]}"#),
+            &AvatarPosType::ZOOM,
+            1,
+        ).unwrap();
+        let num = |v: [i32; 4]| v.iter().map(|n| PosItem::Num(*n)).collect::<Vec<_>>();
+        assert_eq!(expanded.pos.unwrap(), PosDimension::P2D(vec![
+            num([0, 0, 10, 10]),
+            num([5, 11, 20, 10]),
+            num([10, 21, 30, 10]),
+        ]));
+    }
+
+    #[test]
+    fn invalid_keyframes() {
+        let expand = |json: &str| expand_keyframes(&keyframes(json), &AvatarPosType::ZOOM, 1);
+        assert!(expand(r#"{"frames": []}"#).is_err());
+        assert!(expand(r#"{"frames": [{"frame": 0, "pos": [0, 0, 10]}]}"#).is_err());
+        assert!(expand(r#"{"frames": [{"frame": 0, "pos": [0, 0, "width", 10]}]}"#).is_err());
+    }
+}
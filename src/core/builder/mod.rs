@@ -4,6 +4,8 @@ pub mod petpet_builder;
 
 pub mod pos_builder;
 
+pub mod keyframe_builder;
+
 pub mod background_builder;
 
 pub mod text_builder;
\ No newline at end of file
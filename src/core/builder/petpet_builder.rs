@@ -3,7 +3,8 @@ use rayon::prelude::*;
 use skia_safe::Image;
 
 use crate::core::builder::avatar_builder::{AvatarBuilderList, AvatarData};
-use crate::core::builder::background_builder::BackgroundBuilder;
+use crate::core::builder::background_builder::{BackgroundBuilder, OriginSize};
+use crate::core::builder::pos_builder::ExprContext;
 use crate::core::builder::text_builder::TextBuilderList;
 use crate::core::errors::Error;
 use crate::core::loader::image_loader::has_image;
@@ -30,7 +31,9 @@ impl PetpetBuilder {
 
         let avatar_builders = AvatarBuilderList::new(
             template.avatar.clone(),
-            background_builder.length
+            background_builder.length,
+            template.fallback.clone(),
+            template.frame_limit.clone(),
         )?;
 
         let text_builders = TextBuilderList::new(
@@ -45,40 +48,55 @@ impl PetpetBuilder {
         })
     }
 
+    pub fn frame_count(&self) -> usize {
+        self.background_builder.length
+    }
+
+    pub fn canvas_size(&self) -> Option<OriginSize> {
+        self.background_builder.static_size()
+    }
+
     pub async fn build<'a>(&'a self, avatar_data: AvatarData<'a>, text_data: TextData) -> Result<(Vec<Image>, u16), Error> {
         let a_count = self.template.avatar.len();
-        let mut avatar_size = Vec::with_capacity(a_count);
         let mut top_avatars = Vec::with_capacity(a_count);
         let mut bottom_avatars = Vec::with_capacity(a_count);
-        let mut avatar_max_length = 0;
 
-        let avatars = self.avatar_builders.build(avatar_data).await?;
+        // with a background file avatars follow the template delay, otherwise their own timing
+        let (mut avatars, frame_ms) = self.avatar_builders.build(
+            avatar_data,
+            self.background_builder.path.as_ref().map(|_| self.template.delay as u32),
+            self.template.delay as u32,
+        ).await?;
         let texts = self.text_builders.build(&text_data)?;
 
+        let frame_length = avatars.iter()
+            .map(|a| a.get_length())
+            .chain(texts.iter().map(|t| t.get_length()))
+            .fold(0, usize::max);
+        let mut expr_context = ExprContext {
+            avatar_size: avatars.iter().map(|a| a.get_size()).collect(),
+            text_size: texts.iter().map(|t| t.get_size()).collect(),
+            text_lines: texts.iter().map(|t| t.get_line_count()).collect(),
+            canvas_size: None,
+            frames: Some(self.background_builder.output_length(frame_length)),
+        };
+
+        let (mut surface, bgs) = self.background_builder.create_background(&expr_context)?;
+        let bgs = BackgroundBuilder::repeat_for_avatar_length(bgs, frame_length);
+
+        expr_context.canvas_size = Some((surface.width(), surface.height()));
+        for avatar in &mut avatars {
+            avatar.eval_expr(&expr_context)?;
+        }
         for avatar in &avatars {
             if avatar.template.raw.avatar_on_top {
                 top_avatars.push(avatar)
             } else {
                 bottom_avatars.push(avatar)
             }
-            avatar_size.push(avatar.get_size());
-            avatar_max_length = usize::max(avatar_max_length, avatar.get_length());
         }
 
-        let (mut surface, bgs) = self.background_builder.create_background(
-            avatar_size, texts.iter().map(|t| t.get_size()).collect()
-        )?;
-        let bgs = BackgroundBuilder::repeat_for_avatar_length(bgs, avatar_max_length);
-
-        let t_delay = if self.background_builder.path.is_some() {
-            self.template.delay / 10
-        } else {
-            let mut d = 0;
-            for a in &avatars {
-                d += a.delay;
-            }
-            d / a_count as u16 / 10
-        };
+        let t_delay = (frame_ms / 10) as u16;
 
         if MULTITHREADED_DRAWING.to_owned() {
             let info = surface.image_info();
@@ -97,7 +115,7 @@ impl PetpetBuilder {
                     ta.draw(canvas, i).unwrap();
                 }
                 for text in &texts {
-                    text.draw(canvas);
+                    text.draw(canvas, i);
                 }
                 temp_surface.image_snapshot()
             }).collect();
@@ -114,7 +132,7 @@ impl PetpetBuilder {
                     ta.draw(canvas, i)?;
                 }
                 for text in &texts {
-                    text.draw(canvas);
+                    text.draw(canvas, i);
                 }
                 result.push(surface.image_snapshot());
             }
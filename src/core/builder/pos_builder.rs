@@ -6,7 +6,7 @@ use skia_safe::Point;
 use crate::core::builder::background_builder::OriginSize;
 use crate::core::errors::Error;
 use crate::core::errors::Error::TemplateError;
-use crate::core::template::avatar_template::{PosDimension, PosItem};
+use crate::core::template::avatar_template::{FloatItem, PosDimension, PosItem};
 
 pub type XYWH = (i32, i32, i32, i32);
 pub type P = (i32, i32);
@@ -32,6 +32,9 @@ pub fn compile_pos<'a>(origin_pos: PosDimension) -> Result<CompiledPos, Error> {
     let number_pos: CompiledNumberPosDimension = match origin_pos {
         PosDimension::P1D(_) => return Err(TemplateError("".to_string()))?,
         PosDimension::P2D(p2d) => {
+            if p2d.is_empty() {
+                return Err(TemplateError("Missing avatar pos or keyframes".to_string()));
+            }
             let mut result: Vec<XYWH> = Vec::with_capacity(p2d.len());
             for (x_index, xywh) in p2d.iter().enumerate() {
                 if xywh.len() != 4 {
@@ -41,7 +44,7 @@ pub fn compile_pos<'a>(origin_pos: PosDimension) -> Result<CompiledPos, Error> {
                 }
                 let pair: Vec<i32> = xywh.iter().enumerate().map(|(y_index, p)|
                     compile_pos_item(p, &mut expr_pos, (x_index, y_index, 0))
-                ).collect::<Vec<i32>>();
+                ).collect::<Result<Vec<i32>, Error>>()?;
                 result.push((pair[0], pair[1], pair[2], pair[3]))
             }
             CompiledNumberPosDimension::P2D(result)
@@ -62,16 +65,16 @@ pub fn compile_pos<'a>(origin_pos: PosDimension) -> Result<CompiledPos, Error> {
                             format!("deform pos point length must == 2 ({:?})", p)
                         ));
                     }
-                    let x = compile_pos_item(
+                    let x = compile_deform_item(
                         &p[0],
                         &mut expr_pos,
                         (x_index, y_index, 0),
-                    );
-                    let y = compile_pos_item(
+                    )?;
+                    let y = compile_deform_item(
                         &p[1],
                         &mut expr_pos,
                         (x_index, y_index, 1),
-                    );
+                    )?;
                     pair[ri] = (x, y);
                     ri += 1;
                 }
@@ -102,63 +105,200 @@ pub fn compile_pos_item(
     pos_item: &PosItem,
     expr_vec: &mut CompiledExprVec,
     index3d: Expr3DIndex,
-) -> i32 {
+) -> Result<i32, Error> {
     match pos_item {
-        PosItem::Num(p_num) => p_num.clone(),
+        PosItem::Num(p_num) => Ok(p_num.clone()),
         PosItem::Expr(p_str) => {
             expr_vec.push((
-                Expr::from_str(&p_str).unwrap(),
+                Expr::from_str(&p_str)?,
                 index3d
             ));
-            i32::MIN
+            Ok(i32::MIN)
         }
     }
 }
 
-pub fn eval_size<'a>(
+/// expression items are compiled as 0 so that their value can be added
+/// to the absolute points later, see `eval_pos`
+fn compile_deform_item(
+    pos_item: &PosItem,
+    expr_vec: &mut CompiledExprVec,
+    index3d: Expr3DIndex,
+) -> Result<i32, Error> {
+    match compile_pos_item(pos_item, expr_vec, index3d)? {
+        i32::MIN => Ok(0),
+        num => Ok(num),
+    }
+}
+
+/// evaluate expressions for `length` frames, frame `i` uses pos `i % pos.len()`.
+/// `ctx` must contain the avatar variables, `frame` is set here
+pub fn eval_pos(
     (num_pos, expr_vec): (&CompiledNumberPosDimension, &CompiledExprVec),
-    (width, height): OriginSize,
+    ctx: &mut meval::Context,
+    length: usize,
 ) -> Result<CompiledNumberPosDimension, Error> {
-    let mut ctx = meval::Context::new();
-    ctx.var("width", width as f64).var("height", height as f64);
-
-    let num_pos: CompiledNumberPosDimension = num_pos.clone();
     Ok(match num_pos {
-        CompiledNumberPosDimension::P2D(mut p2d) => {
-            for (expr, (x, y, _)) in expr_vec {
-                let num = expr.eval_with_context(&ctx)?.round() as i32;
-                match y {
-                    0 => p2d[*x].0 = num,
-                    1 => p2d[*x].1 = num,
-                    2 => p2d[*x].2 = num,
-                    3 => p2d[*x].3 = num,
-                    _ => return Err(TemplateError("Unknown zoom avatar error".to_string()))
-                };
+        CompiledNumberPosDimension::P2D(num_p2d) => {
+            let mut p2d = Vec::with_capacity(length);
+            for i in 0..usize::max(length, num_p2d.len()) {
+                let x_index = i % num_p2d.len();
+                let mut xywh = num_p2d[x_index];
+                ctx.var("frame", i as f64);
+                for (expr, (x, y, _)) in expr_vec {
+                    if *x != x_index {
+                        continue;
+                    }
+                    let num = expr.eval_with_context(&*ctx)?.round() as i32;
+                    match y {
+                        0 => xywh.0 = num,
+                        1 => xywh.1 = num,
+                        2 => xywh.2 = num,
+                        3 => xywh.3 = num,
+                        _ => return Err(TemplateError("Unknown zoom avatar error".to_string()))
+                    };
+                }
+                p2d.push(xywh);
             }
             CompiledNumberPosDimension::P2D(p2d)
         }
-        CompiledNumberPosDimension::P3D(p3d) => {
-            // for (expr, (x, y, z)) in expr_vec {
-            //     let num = expr.eval_with_context(&ctx)?.round() as i32;
-            //     let p = match y {
-            //         0 => p3d[*x].0,
-            //         1 => p3d[*x].1,
-            //         2 => p3d[*x].2,
-            //         3 => p3d[*x].3,
-            //         4 => p3d[*x].4,
-            //         _ => return Err(TemplateError("Unknown deform avatar y error"))
-            //     };
-            //     match z {
-            //         0 => p.0,
-            //         1 => p.1,
-            //         _ => return Err(TemplateError("Unknown deform avatar z error"))
-            //     };
-            // }
+        CompiledNumberPosDimension::P3D(num_p3d) => {
+            let mut p3d = Vec::with_capacity(length);
+            for i in 0..usize::max(length, num_p3d.len()) {
+                let x_index = i % num_p3d.len();
+                let mut points = num_p3d[x_index];
+                ctx.var("frame", i as f64);
+                for (expr, (x, y, z)) in expr_vec {
+                    if *x != x_index {
+                        continue;
+                    }
+                    let num = expr.eval_with_context(&*ctx)?.round() as f32;
+                    // points are anchor + offset with the expression item as 0,
+                    // an anchor expression moves all four points
+                    let moved: &mut [Point] = match y {
+                        0..=3 => std::slice::from_mut(&mut points[*y]),
+                        4 => &mut points,
+                        _ => return Err(TemplateError("Unknown deform avatar y error".to_string()))
+                    };
+                    for point in moved {
+                        match z {
+                            0 => point.x += num,
+                            1 => point.y += num,
+                            _ => return Err(TemplateError("Unknown deform avatar z error".to_string()))
+                        };
+                    }
+                }
+                p3d.push(points);
+            }
             CompiledNumberPosDimension::P3D(p3d)
         }
     })
 }
 
+/// number or expression of crop and angle fields
+#[derive(Debug, Clone)]
+pub enum CompiledFloat {
+    Num(f32),
+    Expr(Expr),
+}
+
+impl CompiledFloat {
+    pub fn eval(&self, ctx: &meval::Context) -> Result<f32, Error> {
+        match self {
+            CompiledFloat::Num(num) => Ok(*num),
+            CompiledFloat::Expr(expr) => Ok(expr.eval_with_context(ctx)? as f32),
+        }
+    }
+
+    pub fn is_expr(&self) -> bool {
+        matches!(self, CompiledFloat::Expr(_))
+    }
+}
+
+pub fn compile_float(item: &FloatItem) -> Result<CompiledFloat, Error> {
+    Ok(match item {
+        FloatItem::Num(num) => CompiledFloat::Num(*num),
+        FloatItem::Expr(expr) => CompiledFloat::Expr(Expr::from_str(expr)?),
+    })
+}
+
+/// Inputs of position, size, crop and angle expressions.
+///
+/// Variables by field:
+/// - avatar `pos` and `angle`: all of the below, `frame` is the output frame index
+/// - background `size`: `frames` (with `frame` fixed to 0), `avatarN*` and `textN*`
+/// - avatar `crop`: only its own `width`, `height` and `aspect`
+///
+/// - `width`, `height`, `aspect`: uncropped size of the avatar the field belongs to
+/// - `frame`, `frames`: output frame index and count
+/// - `canvasWidth`, `canvasHeight`
+/// - `avatarNWidth`, `avatarNHeight`, `avatarNAspect`: cropped avatars in template order, from 0
+/// - `textNWidth`, `textNHeight`, `textNLines`: texts in template order, from 0
+///
+/// Functions: meval builtins (`sin`, `cos`, `abs`, `sqrt`, `min`, `max`, `floor`, `round`, `pi`...),
+/// `lerp(a, b, t)`, `clamp(x, min, max)` and `ease_in_out(t)`.
+///
+/// Each step only sees what is already known: the crop decides `avatarN*`, which the
+/// background size may use, and the background size is the canvas size.
+#[derive(Debug, Clone, Default)]
+pub struct ExprContext {
+    pub avatar_size: Vec<OriginSize>,
+    pub text_size: Vec<OriginSize>,
+    pub text_lines: Vec<usize>,
+    pub canvas_size: Option<OriginSize>,
+    pub frames: Option<usize>,
+}
+
+impl ExprContext {
+    pub fn to_meval<'a>(&self) -> meval::Context<'a> {
+        let mut ctx = helper_context();
+        for (i, (w, h)) in self.avatar_size.iter().enumerate() {
+            ctx.var(format!("avatar{}Width", i), *w as f64)
+                .var(format!("avatar{}Height", i), *h as f64)
+                .var(format!("avatar{}Aspect", i), aspect((*w, *h)));
+        }
+        for (i, (w, h)) in self.text_size.iter().enumerate() {
+            ctx.var(format!("text{}Width", i), *w as f64)
+                .var(format!("text{}Height", i), *h as f64);
+        }
+        for (i, lines) in self.text_lines.iter().enumerate() {
+            ctx.var(format!("text{}Lines", i), *lines as f64);
+        }
+        if let Some((w, h)) = self.canvas_size {
+            ctx.var("canvasWidth", w as f64).var("canvasHeight", h as f64);
+        }
+        if let Some(frames) = self.frames {
+            ctx.var("frames", frames as f64).var("frame", 0.0);
+        }
+        ctx
+    }
+}
+
+/// meval builtins with interpolation helpers
+pub fn helper_context<'a>() -> meval::Context<'a> {
+    let mut ctx = meval::Context::new();
+    ctx.func3("lerp", |a, b, t| a + (b - a) * t)
+        .func3("clamp", |x, min, max| x.max(min).min(max))
+        .func("ease_in_out", ease_in_out);
+    ctx
+}
+
+/// quadratic, `t` is clamped to 0..1
+pub fn ease_in_out(t: f64) -> f64 {
+    let t = t.clamp(0.0, 1.0);
+    if t < 0.5 { 2.0 * t * t } else { 1.0 - (-2.0 * t + 2.0).powi(2) / 2.0 }
+}
+
+/// set `width`, `height` and `aspect` of the current avatar
+pub fn set_size_vars(ctx: &mut meval::Context, size: OriginSize) {
+    ctx.var("width", size.0 as f64)
+        .var("height", size.1 as f64)
+        .var("aspect", aspect(size));
+}
+
+fn aspect((w, h): OriginSize) -> f64 {
+    if h == 0 { 0.0 } else { w as f64 / h as f64 }
+}
 
 pub type CompiledSizeExpr = (Expr, usize);
 
@@ -193,19 +333,9 @@ pub fn compile_size(
 
 pub fn eval_background_size(
     (size, expr_vec): &CompiledSize,
-    avatar_size: Vec<OriginSize>,
-    text_size: Vec<OriginSize>,
+    ctx: &ExprContext,
 ) -> Result<OriginSize, Error> {
-    let mut ctx = meval::Context::new();
-
-    for (i, (w, h)) in avatar_size.iter().enumerate() {
-        ctx.var(format!("avatar{}Width", i), *w as f64)
-            .var(format!("avatar{}Height", i), *h as f64);
-    }
-    for (i, (w, h)) in text_size.iter().enumerate() {
-        ctx.var(format!("text{}Width", i), *w as f64)
-            .var(format!("text{}Height", i), *h as f64);
-    }
+    let ctx = ctx.to_meval();
 
     let mut result = size.clone();
     for (expr, index) in expr_vec {
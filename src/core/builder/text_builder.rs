@@ -2,6 +2,7 @@ use skia_safe::{Paint, PaintStyle};
 use skia_safe::textlayout::ParagraphStyle;
 
 use crate::core::errors::Error;
+use crate::core::errors::Error::TemplateError;
 use crate::core::loader::color_util::parse_color;
 use crate::core::model::text_model::TextModel;
 use crate::core::template::text_template::{TextData, TextTemplate};
@@ -12,23 +13,38 @@ pub struct TextBuilder {
 
 pub struct TextBuiltTemplate {
     pub raw: TextTemplate,
-    pub fill_paint: Option<Paint>,
+    /// per frame, `None` for a transparent color
+    pub fill_paint: Vec<Option<Paint>>,
     pub stroke_paint: Option<Paint>,
     pub paragraph_style: ParagraphStyle,
+    /// longest of the per-frame lists or the end of `visible`
+    pub frame_length: usize,
 }
 
 impl TextBuilder {
     pub fn new(mut template: TextTemplate) -> Result<Self, Error> {
-        template.angle %= 360.0;
-        let fill_color = parse_color(&template.color)?;
-        let fill_paint = if fill_color.a() != 0 {
-            let mut paint = Paint::default();
-            paint.set_color(fill_color);
-            paint.set_style(PaintStyle::StrokeAndFill);
-            Some(paint)
-        } else {
-            None
-        };
+        if template.pos.is_empty() || template.size.is_empty()
+            || template.angle.is_empty() || template.color.is_empty() {
+            return Err(TemplateError("text pos, size, angle and color lists must not be empty".to_string()));
+        }
+        if template.visible.len() > 2 {
+            return Err(TemplateError(format!("text visible must be [start] or [start, end] ({:?})", template.visible)));
+        }
+        for angle in &mut template.angle {
+            *angle %= 360.0;
+        }
+        let mut fill_paint = Vec::with_capacity(template.color.len());
+        for color in &template.color {
+            let fill_color = parse_color(color)?;
+            fill_paint.push(if fill_color.a() != 0 {
+                let mut paint = Paint::default();
+                paint.set_color(fill_color);
+                paint.set_style(PaintStyle::StrokeAndFill);
+                Some(paint)
+            } else {
+                None
+            });
+        }
         let stroke_paint = if template.stroke_size != 0.0 {
             let stroke_color = parse_color(&template.stroke_color)?;
             let mut paint = Paint::default();
@@ -42,6 +58,13 @@ impl TextBuilder {
         };
         let mut paragraph_style = ParagraphStyle::new();
         paragraph_style.set_text_align(template.align.to_skia_align());
+        let frame_length = [
+            template.pos.len(),
+            template.size.len(),
+            template.angle.len(),
+            template.color.len(),
+            template.visible.last().map_or(0, |end| end + 1),
+        ].into_iter().fold(1, usize::max);
 
         Ok(TextBuilder {
             built_template: TextBuiltTemplate {
@@ -49,6 +72,7 @@ impl TextBuilder {
                 fill_paint,
                 stroke_paint,
                 paragraph_style,
+                frame_length,
             },
         })
     }
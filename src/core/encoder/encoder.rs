@@ -1,8 +1,14 @@
+use std::str::FromStr;
+
 use gif::{DisposalMethod, Frame, Repeat};
 use once_cell::sync::Lazy;
 use rayon::prelude::*;
-use skia_safe::{EncodedImageFormat, Image};
+use serde::{Deserialize, Serialize};
+use skia_safe::{AlphaType, ColorType, EncodedImageFormat, Image, ImageInfo};
+use skia_safe::image::CachingHint;
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
 
+use crate::core::encoder::optimized_gif::encode_optimized_gif;
 use crate::core::errors::Error;
 use crate::core::errors::Error::ImageEncodeError;
 
@@ -12,10 +18,20 @@ pub static IMAGE_ENCODER: Lazy<ImageEncoder> = Lazy::new(|| {
 
 pub static PNG_FORMAT: &'static str = "png";
 pub static GIF_FORMAT: &'static str = "gif";
+pub static WEBP_FORMAT: &'static str = "webp";
+pub static APNG_FORMAT: &'static str = "apng";
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
 pub enum EncodeFormat {
+    #[serde(alias = "png")]
     PNG,
-    GIF
+    #[serde(alias = "gif")]
+    GIF,
+    #[serde(alias = "webp")]
+    WEBP,
+    #[serde(alias = "apng")]
+    APNG,
 }
 
 impl EncodeFormat {
@@ -23,18 +39,62 @@ impl EncodeFormat {
         match self {
             EncodeFormat::PNG => PNG_FORMAT,
             EncodeFormat::GIF => GIF_FORMAT,
+            EncodeFormat::WEBP => WEBP_FORMAT,
+            EncodeFormat::APNG => APNG_FORMAT,
         }
     }
 
     pub fn to_format(&self) -> String {
         format!("image/{}", self.get_str())
     }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime.trim().to_ascii_lowercase().as_str() {
+            "image/png" => Some(EncodeFormat::PNG),
+            "image/gif" => Some(EncodeFormat::GIF),
+            "image/webp" => Some(EncodeFormat::WEBP),
+            "image/apng" => Some(EncodeFormat::APNG),
+            _ => None
+        }
+    }
+}
+
+impl FromStr for EncodeFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(EncodeFormat::PNG),
+            "gif" => Ok(EncodeFormat::GIF),
+            "webp" => Ok(EncodeFormat::WEBP),
+            "apng" => Ok(EncodeFormat::APNG),
+            _ => Err(ImageEncodeError(format!("Unsupported format: {}", s)))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EncodeOptions {
+    /// decide by frame count if `None`
+    #[serde(default)]
+    pub format: Option<EncodeFormat>,
+    /// PNG / WebP quality, 0 - 100
+    #[serde(default)]
+    pub quality: Option<u32>,
+    /// GIF quantization speed, 1 (best) - 30 (fastest)
+    #[serde(default)]
+    pub speed: Option<i32>,
+    /// GIF with global palette and frame diff
+    #[serde(default)]
+    pub optimize: Option<bool>,
 }
 
 pub struct ImageEncoder {
     // context: Option<DirectContext>,
     png_quality: u32,
     gif_quality: i32,
+    gif_optimize: bool,
+    webp_quality: f32,
 }
 
 impl ImageEncoder {
@@ -43,62 +103,302 @@ impl ImageEncoder {
         ImageEncoder {
             // context: DirectContext::new_gl(None, None),
             png_quality: 90,
-            gif_quality: 10
+            gif_quality: 10,
+            gif_optimize: false,
+            webp_quality: 80.0,
         }
     }
 
     pub fn encode(&self, images: &Vec<Image>, delay: u16)
         -> Result<(Vec<u8>, EncodeFormat), Error>
     {
-        if images.len() == 1 {
-            Ok((self.encode_image(&images[0])?, EncodeFormat::PNG))
-        } else {
-            Ok((self.encode_images(images, delay)?, EncodeFormat::GIF))
+        self.encode_with(images, delay, &EncodeOptions::default())
+    }
+
+    /// encode with requested format, returns the format actually used
+    pub fn encode_as(&self, images: &Vec<Image>, delay: u16, format: EncodeFormat)
+        -> Result<(Vec<u8>, EncodeFormat), Error>
+    {
+        self.encode_with(images, delay, &EncodeOptions {
+            format: Some(format),
+            ..EncodeOptions::default()
+        })
+    }
+
+    /// options override the encoder defaults, returns the format actually used
+    pub fn encode_with(&self, images: &Vec<Image>, delay: u16, options: &EncodeOptions)
+        -> Result<(Vec<u8>, EncodeFormat), Error>
+    {
+        let single = images.len() == 1;
+        let format = options.format.unwrap_or(
+            if single { EncodeFormat::PNG } else { EncodeFormat::GIF }
+        );
+        match format {
+            EncodeFormat::PNG | EncodeFormat::APNG if single => {
+                let quality = options.quality.unwrap_or(self.png_quality).min(100);
+                Ok((encode_png(&images[0], quality)?, EncodeFormat::PNG))
+            }
+            // PNG can not hold animation, fallback to GIF
+            EncodeFormat::PNG | EncodeFormat::GIF => {
+                let speed = options.speed.unwrap_or(self.gif_quality).clamp(1, 30);
+                Ok((if options.optimize.unwrap_or(self.gif_optimize) {
+                    encode_optimized_gif(images, delay, speed)?
+                } else {
+                    encode_gif(images, delay, speed)?
+                }, EncodeFormat::GIF))
+            }
+            EncodeFormat::WEBP => {
+                let quality = options.quality
+                    .map(|q| q.min(100) as f32)
+                    .unwrap_or(self.webp_quality);
+                Ok((if single {
+                    encode_webp(&images[0], quality)?
+                } else {
+                    encode_animated_webp(images, delay, quality)?
+                }, EncodeFormat::WEBP))
+            }
+            EncodeFormat::APNG => Ok((encode_apng(images, delay)?, EncodeFormat::APNG)),
         }
     }
 
     pub fn encode_image(&self, image: &Image) -> Result<Vec<u8>, Error> {
-        let data = image.encode(
-            // &self.context,
-            // &DirectContext::new_gl(None, None),
-            None,
-            EncodedImageFormat::PNG,
-            self.png_quality
-        ).ok_or(ImageEncodeError("".to_string()))?;
-        Ok(data.as_bytes().to_owned())
+        encode_png(image, self.png_quality)
     }
 
     pub fn encode_images(&self, images: &Vec<Image>, delay: u16) -> Result<Vec<u8>, Error> {
-        let mut bytes = Vec::with_capacity(65536);
-        {
-            let mut encoder = gif::Encoder::new(
-                &mut bytes,
-                images[0].width() as u16,
-                images[0].height() as u16,
-                &[]
-            ).unwrap();
-            encoder.set_repeat(Repeat::Infinite).or_else(|_| Err(ImageEncodeError("".to_string())))?;
-
-            let frames: Vec<Frame> = images.par_iter().map(|img| {
-                let map = img.peek_pixels().unwrap();
-                let mut ps = map.bytes().unwrap().to_owned();
-
-                let mut frame = Frame::from_rgba_speed(
-                    img.width() as u16,
-                    img.height() as u16,
-                    &mut ps,
-                    self.gif_quality,
-                );
-                frame.dispose = DisposalMethod::Background;
-                frame.delay = delay;
-                frame.make_lzw_pre_encoded();
-                frame
-            }).collect();
-            for frame in frames {
-                encoder.write_lzw_pre_encoded_frame(&frame).or_else(|_| Err(ImageEncodeError("".to_string())))?;
+        encode_gif(images, delay, self.gif_quality)
+    }
+
+    pub fn encode_webp_image(&self, image: &Image) -> Result<Vec<u8>, Error> {
+        encode_webp(image, self.webp_quality)
+    }
+
+    /// delay in 1/100 sec, same as GIF
+    pub fn encode_webp_images(&self, images: &Vec<Image>, delay: u16) -> Result<Vec<u8>, Error> {
+        encode_animated_webp(images, delay, self.webp_quality)
+    }
+}
+
+fn encode_png(image: &Image, quality: u32) -> Result<Vec<u8>, Error> {
+    let data = image.encode(
+        // &self.context,
+        // &DirectContext::new_gl(None, None),
+        None,
+        EncodedImageFormat::PNG,
+        quality
+    ).ok_or(ImageEncodeError("".to_string()))?;
+    Ok(data.as_bytes().to_owned())
+}
+
+fn encode_gif(images: &Vec<Image>, delay: u16, speed: i32) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::with_capacity(65536);
+    {
+        let mut encoder = gif::Encoder::new(
+            &mut bytes,
+            images[0].width() as u16,
+            images[0].height() as u16,
+            &[]
+        ).unwrap();
+        encoder.set_repeat(Repeat::Infinite).or_else(|_| Err(ImageEncodeError("".to_string())))?;
+
+        let frames: Vec<Frame> = images.par_iter().map(|img| {
+            let map = img.peek_pixels().unwrap();
+            let mut ps = map.bytes().unwrap().to_owned();
+
+            let mut frame = Frame::from_rgba_speed(
+                img.width() as u16,
+                img.height() as u16,
+                &mut ps,
+                speed,
+            );
+            frame.dispose = DisposalMethod::Background;
+            frame.delay = delay;
+            frame.make_lzw_pre_encoded();
+            frame
+        }).collect();
+        for frame in frames {
+            encoder.write_lzw_pre_encoded_frame(&frame).or_else(|_| Err(ImageEncodeError("".to_string())))?;
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn encode_webp(image: &Image, quality: f32) -> Result<Vec<u8>, Error> {
+    let pixels = unpremul_pixels(image)?;
+    let memory = webp::Encoder::from_rgba(
+        &pixels,
+        image.width() as u32,
+        image.height() as u32,
+    ).encode(quality);
+    Ok(memory.to_vec())
+}
+
+fn encode_animated_webp(images: &Vec<Image>, delay: u16, quality: f32) -> Result<Vec<u8>, Error> {
+    let mut config = WebPConfig::new()
+        .map_err(|_| ImageEncodeError("Can not init WebP config".to_string()))?;
+    config.lossless = 0;
+    config.quality = quality;
+
+    let width = images[0].width() as u32;
+    let height = images[0].height() as u32;
+    let frame_pixels = images.par_iter()
+        .map(|img| unpremul_pixels(img))
+        .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(0);
+    for (i, pixels) in frame_pixels.iter().enumerate() {
+        encoder.add_frame(AnimFrame::from_rgba(
+            pixels, width, height,
+            i as i32 * delay as i32 * 10,
+        ));
+    }
+    let memory = encoder.try_encode()
+        .map_err(|e| ImageEncodeError(format!("{:?}", e)))?;
+    Ok(memory.to_vec())
+}
+
+/// lossless, full alpha animation
+fn encode_apng(images: &Vec<Image>, delay: u16) -> Result<Vec<u8>, Error> {
+    let width = images[0].width() as u32;
+    let height = images[0].height() as u32;
+    let frame_pixels = images.par_iter()
+        .map(|img| unpremul_pixels(img))
+        .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+
+    let map_err = |e: png::EncodingError| ImageEncodeError(e.to_string());
+    let mut bytes = Vec::with_capacity(65536);
+    {
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Default);
+        encoder.set_animated(frame_pixels.len() as u32, 0).map_err(map_err)?;
+        encoder.set_frame_delay(delay, 100).map_err(map_err)?;
+        encoder.set_dispose_op(png::DisposeOp::None).map_err(map_err)?;
+        encoder.set_blend_op(png::BlendOp::Source).map_err(map_err)?;
+
+        let mut writer = encoder.write_header().map_err(map_err)?;
+        for pixels in &frame_pixels {
+            writer.write_image_data(pixels).map_err(map_err)?;
+        }
+        writer.finish().map_err(map_err)?;
+    }
+
+    Ok(bytes)
+}
+
+pub(crate) fn unpremul_pixels(image: &Image) -> Result<Vec<u8>, Error> {
+    let info = ImageInfo::new(
+        image.dimensions(),
+        ColorType::RGBA8888,
+        AlphaType::Unpremul,
+        None,
+    );
+    let row_bytes = info.min_row_bytes();
+    let mut pixels = vec![0u8; info.compute_min_byte_size()];
+    if image.read_pixels(&info, &mut pixels, row_bytes, (0, 0), CachingHint::Allow) {
+        Ok(pixels)
+    } else {
+        Err(ImageEncodeError("Can not read image pixels".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use skia_safe::{Color, surfaces};
+
+    use super::*;
+
+    static PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    fn images(count: usize) -> Vec<Image> {
+        let mut surface = surfaces::raster_n32_premul((8, 8)).unwrap();
+        (0..count).map(|i| {
+            surface.canvas().clear(Color::from_rgb((i * 40) as u8, 0, 0));
+            surface.image_snapshot()
+        }).collect()
+    }
+
+    fn encode(count: usize, format: Option<EncodeFormat>, optimize: bool) -> (Vec<u8>, EncodeFormat) {
+        IMAGE_ENCODER.encode_with(&images(count), 10, &EncodeOptions {
+            format,
+            optimize: Some(optimize),
+            ..EncodeOptions::default()
+        }).unwrap()
+    }
+
+    fn is_webp(blob: &[u8]) -> bool {
+        blob.starts_with(b"RIFF") && &blob[8..12] == b"WEBP"
+    }
+
+    fn has_chunk(blob: &[u8], chunk: &[u8]) -> bool {
+        blob.windows(chunk.len()).any(|w| w == chunk)
+    }
+
+    #[test]
+    fn default_format_by_frame_count() {
+        let (blob, format) = encode(1, None, false);
+        assert_eq!(format, EncodeFormat::PNG);
+        assert!(blob.starts_with(PNG_MAGIC));
+        let (blob, format) = encode(3, None, false);
+        assert_eq!(format, EncodeFormat::GIF);
+        assert!(blob.starts_with(b"GIF8"));
+    }
+
+    #[test]
+    fn png() {
+        let (blob, format) = encode(1, Some(EncodeFormat::PNG), false);
+        assert_eq!(format, EncodeFormat::PNG);
+        assert!(blob.starts_with(PNG_MAGIC));
+        // animated PNG falls back to GIF
+        let (blob, format) = encode(3, Some(EncodeFormat::PNG), false);
+        assert_eq!(format, EncodeFormat::GIF);
+        assert!(blob.starts_with(b"GIF8"));
+    }
+
+    #[test]
+    fn gif() {
+        for optimize in [false, true] {
+            for count in [1, 3] {
+                let (blob, format) = encode(count, Some(EncodeFormat::GIF), optimize);
+                assert_eq!(format, EncodeFormat::GIF);
+                assert!(blob.starts_with(b"GIF8"));
             }
         }
+    }
+
+    #[test]
+    fn webp() {
+        let (blob, format) = encode(1, Some(EncodeFormat::WEBP), false);
+        assert_eq!(format, EncodeFormat::WEBP);
+        assert!(is_webp(&blob));
+        let (blob, format) = encode(3, Some(EncodeFormat::WEBP), false);
+        assert_eq!(format, EncodeFormat::WEBP);
+        assert!(is_webp(&blob));
+        assert!(has_chunk(&blob, b"ANIM"));
+    }
 
-        Ok(bytes)
+    #[test]
+    fn apng() {
+        let (blob, format) = encode(3, Some(EncodeFormat::APNG), false);
+        assert_eq!(format, EncodeFormat::APNG);
+        assert!(blob.starts_with(PNG_MAGIC));
+        assert!(has_chunk(&blob, b"acTL"));
+        // a single frame is a plain PNG
+        let (blob, format) = encode(1, Some(EncodeFormat::APNG), false);
+        assert_eq!(format, EncodeFormat::PNG);
+        assert!(!has_chunk(&blob, b"acTL"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn format_names() {
+        for format in [EncodeFormat::PNG, EncodeFormat::GIF, EncodeFormat::WEBP, EncodeFormat::APNG] {
+            assert_eq!(EncodeFormat::from_str(format.get_str()).unwrap(), format);
+            assert_eq!(EncodeFormat::from_mime(&format.to_format()), Some(format));
+        }
+        assert_eq!(EncodeFormat::from_str("WebP").unwrap(), EncodeFormat::WEBP);
+        assert!(EncodeFormat::from_str("bmp").is_err());
+    }
+}
@@ -1 +1,2 @@
-pub mod encoder;
\ No newline at end of file
+pub mod encoder;
+mod optimized_gif;
\ No newline at end of file
@@ -0,0 +1,141 @@
+use std::borrow::Cow;
+
+use color_quant::NeuQuant;
+use gif::{DisposalMethod, Frame, Repeat};
+use rayon::prelude::*;
+use skia_safe::Image;
+
+use crate::core::encoder::encoder::unpremul_pixels;
+use crate::core::errors::Error;
+use crate::core::errors::Error::ImageEncodeError;
+
+static TRANSPARENT_INDEX: u8 = 255;
+static MAX_SAMPLE_PIXELS: usize = 1 << 20;
+
+/// GIF with a shared global palette,
+/// frames only contain the changed sub-rectangle, unchanged pixels are transparent
+pub fn encode_optimized_gif(images: &Vec<Image>, delay: u16, speed: i32) -> Result<Vec<u8>, Error> {
+    let width = images[0].width() as usize;
+    let height = images[0].height() as usize;
+    let frame_pixels = images.par_iter()
+        .map(|img| unpremul_pixels(img))
+        .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+
+    let quant = build_quant(&frame_pixels, speed);
+    let mut palette = quant.color_map_rgb();
+    palette.resize(256 * 3, 0);
+
+    let indexed: Vec<Vec<u8>> = frame_pixels.par_iter()
+        .map(|pixels| pixels.chunks_exact(4).map(|p| if p[3] < 128 {
+            TRANSPARENT_INDEX
+        } else {
+            quant.index_of(&[p[0], p[1], p[2], 255]) as u8
+        }).collect())
+        .collect();
+    drop(frame_pixels);
+
+    let len = indexed.len();
+    // frame can not be drawn over the previous one if some pixel turns transparent,
+    // the previous frame has to clear the canvas instead
+    let needs_clear: Vec<bool> = (0..len).into_par_iter().map(|i| {
+        let prev = &indexed[(i + len - 1) % len];
+        len > 1 && indexed[i].iter().zip(prev.iter())
+            .any(|(c, p)| *c == TRANSPARENT_INDEX && *p != TRANSPARENT_INDEX)
+    }).collect();
+
+    let frames: Vec<Frame> = (0..len).into_par_iter().map(|i| {
+        let clear_after = needs_clear[(i + 1) % len];
+        let mut frame = if i == 0 || needs_clear[i] || clear_after {
+            Frame {
+                width: width as u16,
+                height: height as u16,
+                buffer: Cow::Owned(indexed[i].clone()),
+                ..Frame::default()
+            }
+        } else {
+            diff_frame(&indexed[i - 1], &indexed[i], width, height)
+        };
+        frame.dispose = if clear_after {
+            DisposalMethod::Background
+        } else {
+            DisposalMethod::Keep
+        };
+        frame.delay = delay;
+        frame.transparent = Some(TRANSPARENT_INDEX);
+        frame.make_lzw_pre_encoded();
+        frame
+    }).collect();
+
+    let mut bytes = Vec::with_capacity(65536);
+    {
+        let mut encoder = gif::Encoder::new(
+            &mut bytes,
+            width as u16,
+            height as u16,
+            &palette,
+        ).or_else(|_| Err(ImageEncodeError("".to_string())))?;
+        encoder.set_repeat(Repeat::Infinite).or_else(|_| Err(ImageEncodeError("".to_string())))?;
+        for frame in frames {
+            encoder.write_lzw_pre_encoded_frame(&frame).or_else(|_| Err(ImageEncodeError("".to_string())))?;
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn build_quant(frame_pixels: &Vec<Vec<u8>>, speed: i32) -> NeuQuant {
+    let total: usize = frame_pixels.iter().map(|p| p.len() / 4).sum();
+    let step = usize::max(1, total / MAX_SAMPLE_PIXELS);
+    let mut samples = Vec::with_capacity(usize::min(total, MAX_SAMPLE_PIXELS + 1) * 4);
+    for p in frame_pixels.iter().flat_map(|p| p.chunks_exact(4)).step_by(step) {
+        if p[3] >= 128 {
+            samples.extend_from_slice(&[p[0], p[1], p[2], 255]);
+        }
+    }
+    if samples.is_empty() {
+        samples.extend_from_slice(&[0, 0, 0, 255]);
+    }
+    // last index is reserved for transparency
+    NeuQuant::new(speed, 255, &samples)
+}
+
+fn diff_frame(prev: &[u8], current: &[u8], width: usize, height: usize) -> Frame<'static> {
+    let (mut x0, mut y0, mut x1, mut y1) = (width, height, 0, 0);
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            if prev[i] != current[i] {
+                x0 = x0.min(x);
+                y0 = y0.min(y);
+                x1 = x1.max(x);
+                y1 = y1.max(y);
+            }
+        }
+    }
+
+    if x0 > x1 {
+        // nothing changed, keep the frame for timing
+        return Frame {
+            width: 1,
+            height: 1,
+            buffer: Cow::Owned(vec![TRANSPARENT_INDEX]),
+            ..Frame::default()
+        };
+    }
+
+    let mut buffer = Vec::with_capacity((x1 - x0 + 1) * (y1 - y0 + 1));
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let i = y * width + x;
+            buffer.push(if prev[i] == current[i] { TRANSPARENT_INDEX } else { current[i] });
+        }
+    }
+    Frame {
+        left: x0 as u16,
+        top: y0 as u16,
+        width: (x1 - x0 + 1) as u16,
+        height: (y1 - y0 + 1) as u16,
+        buffer: Cow::Owned(buffer),
+        ..Frame::default()
+    }
+}
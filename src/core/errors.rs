@@ -3,16 +3,28 @@ pub enum Error {
     RequestError(reqwest::Error),
     UrlParseError(url::ParseError),
     ImageDecodeError(String),
+    /// undecodable avatar sent by the client as upload, base64 or data uri
+    UploadDecodeError(String),
     ImageSynthesisError(String),
     ImageEncodeError(String),
     FileError(String),
     TemplateError(String),
     AvatarLoadError(String),
+    AvatarNotFoundError(String),
     EvalPosError(meval::Error),
     MissingDataError(String),
+    NotFoundError(String),
+    BadRequestError(String),
+    UnauthorizedError(String),
+    AvatarTooLargeError(String),
+    ContentTypeError(String),
+    RequestTimeoutError(String),
+    UrlPolicyError(String),
     SyncPoisonError(std::sync::PoisonError<String>),
     SerializationError(serde_json::Error),
     IOError(std::io::Error),
+    /// one failure handed to every caller sharing a download
+    SharedError(std::sync::Arc<Error>),
 }
 
 impl<'a> std::fmt::Display for Error {
@@ -21,16 +33,26 @@ impl<'a> std::fmt::Display for Error {
             Error::RequestError(err) => write!(f, "Request error: {}", err),
             Error::UrlParseError(err) => write!(f, "URL Parse error: {}", err),
             Error::ImageDecodeError(msg) => write!(f, "Decode error: {}", msg),
+            Error::UploadDecodeError(msg) => write!(f, "Decode error: {}", msg),
             Error::ImageSynthesisError(msg) => write!(f, "Synthesis error: {}", msg),
             Error::ImageEncodeError(msg) => write!(f, "Encode error: {}", msg),
             Error::FileError(msg) => write!(f, "File error: {}", msg),
             Error::TemplateError(msg) => write!(f, "Template error: {}", msg),
             Error::AvatarLoadError(msg) => write!(f, "Avatar load error: {}", msg),
+            Error::AvatarNotFoundError(msg) => write!(f, "Avatar not found error: {}", msg),
             Error::EvalPosError(err) => write!(f, "Eval pos error: {}", err),
             Error::MissingDataError(msg) => write!(f, "Missing data error: {}", msg),
+            Error::NotFoundError(msg) => write!(f, "Not found error: {}", msg),
+            Error::BadRequestError(msg) => write!(f, "Bad request error: {}", msg),
+            Error::UnauthorizedError(msg) => write!(f, "Unauthorized error: {}", msg),
+            Error::AvatarTooLargeError(msg) => write!(f, "Avatar too large error: {}", msg),
+            Error::ContentTypeError(msg) => write!(f, "Content type error: {}", msg),
+            Error::RequestTimeoutError(msg) => write!(f, "Request timeout error: {}", msg),
+            Error::UrlPolicyError(msg) => write!(f, "URL policy error: {}", msg),
             Error::SyncPoisonError(err) => write!(f, "Sync poison error: {}", err),
             Error::SerializationError(err) => write!(f, "Serialization error: {}", err),
             Error::IOError(err) => write!(f, "IO error: {}", err),
+            Error::SharedError(err) => write!(f, "{}", err),
         }
     }
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::future::{BoxFuture, FutureExt, Shared, WeakShared};
+use once_cell::sync::Lazy;
+use reqwest::header::{CACHE_CONTROL, ETAG, HeaderMap, LAST_MODIFIED};
+use schnellru::{ByLength, LruMap};
+
+use crate::core::builder::avatar_builder::AvatarFrames;
+use crate::core::errors::Error;
+
+static MAX_CACHE_LENGTH: u32 = 4096;
+
+type DownloadFuture = BoxFuture<'static, Result<AvatarFrames, Arc<Error>>>;
+
+/// one download awaited by every concurrent request for the same url
+pub type SharedDownload = Shared<DownloadFuture>;
+
+pub struct AvatarCacheOptions {
+    /// default and upper bound of `Cache-Control: max-age`, zero disables the cache
+    pub ttl: Duration,
+    /// decoded pixel bytes, zero disables the cache
+    pub max_size: usize,
+}
+
+#[derive(Clone)]
+pub struct CachedAvatar {
+    pub frames: AvatarFrames,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    expires: Instant,
+    size: usize,
+}
+
+impl CachedAvatar {
+    pub fn is_fresh(&self) -> bool {
+        Instant::now() < self.expires
+    }
+}
+
+struct CacheState {
+    map: LruMap<String, CachedAvatar, ByLength>,
+    size: usize,
+}
+
+/// decoded avatars keyed by url, bounded by total pixel bytes
+pub struct AvatarCache {
+    options: RwLock<AvatarCacheOptions>,
+    state: Mutex<CacheState>,
+    /// weak so a download nobody waits for any more is dropped
+    in_flight: Mutex<HashMap<String, (u64, WeakShared<DownloadFuture>)>>,
+    next_id: AtomicU64,
+}
+
+pub(crate) static AVATAR_CACHE: Lazy<AvatarCache> = Lazy::new(|| AvatarCache::new(AvatarCacheOptions {
+    ttl: Duration::from_secs(300),
+    max_size: 256 * 1024 * 1024,
+}));
+
+pub fn set_avatar_cache_options(options: AvatarCacheOptions) -> Result<(), Error> {
+    AVATAR_CACHE.set_options(options)
+}
+
+impl AvatarCache {
+    pub fn new(options: AvatarCacheOptions) -> AvatarCache {
+        AvatarCache {
+            options: RwLock::new(options),
+            state: Mutex::new(CacheState {
+                map: LruMap::new(ByLength::new(MAX_CACHE_LENGTH)),
+                size: 0,
+            }),
+            in_flight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_options(&self, options: AvatarCacheOptions) -> Result<(), Error> {
+        *self.options.write()? = options;
+        let mut state = self.state.lock()?;
+        state.map.clear();
+        state.size = 0;
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.options.read().map_or(false, |o| !o.ttl.is_zero() && o.max_size != 0)
+    }
+
+    pub fn get(&self, url: &str) -> Option<CachedAvatar> {
+        self.state.lock().ok()?.map.get(url).cloned()
+    }
+
+    /// `no-store` responses are not cached, `no-cache` ones are kept for revalidation only
+    pub fn insert(&self, url: &str, frames: AvatarFrames, headers: &HeaderMap) -> Result<(), Error> {
+        let ttl = match self.ttl_for(headers)? {
+            Some(ttl) => ttl,
+            None => return Ok(()),
+        };
+        let max_size = self.options.read()?.max_size;
+        let size: usize = frames.0.iter()
+            .map(|img| img.width() as usize * img.height() as usize * 4)
+            .sum();
+        if size > max_size {
+            return Ok(());
+        }
+        let avatar = CachedAvatar {
+            frames,
+            etag: header_str(headers, ETAG),
+            last_modified: header_str(headers, LAST_MODIFIED),
+            expires: Instant::now() + ttl,
+            size,
+        };
+
+        let mut state = self.state.lock()?;
+        if let Some(old) = state.map.remove(url) {
+            state.size -= old.size;
+        }
+        while state.size + size > max_size || state.map.len() >= MAX_CACHE_LENGTH as usize {
+            match state.map.pop_oldest() {
+                Some((_, old)) => state.size -= old.size,
+                None => break,
+            }
+        }
+        state.size += size;
+        state.map.insert(url.to_string(), avatar);
+        Ok(())
+    }
+
+    /// extend a stale entry after `304 Not Modified`
+    pub fn refresh(&self, url: &str, headers: &HeaderMap) -> Result<(), Error> {
+        let ttl = self.ttl_for(headers)?.unwrap_or(Duration::ZERO);
+        if let Some(avatar) = self.state.lock()?.map.get(url) {
+            avatar.expires = Instant::now() + ttl;
+        }
+        Ok(())
+    }
+
+    /// join the download of `url` in progress, or start one with `download`.
+    /// the entry is removed once it finishes, fails or every waiter is dropped
+    pub fn in_flight<F>(&'static self, url: &str, download: F) -> Result<SharedDownload, Error>
+        where F: FnOnce() -> BoxFuture<'static, Result<AvatarFrames, Error>>
+    {
+        let mut in_flight = self.in_flight.lock()?;
+        if let Some(shared) = in_flight.get(url).and_then(|(_, weak)| weak.upgrade()) {
+            return Ok(shared);
+        }
+        let guard = InFlightGuard {
+            cache: self,
+            url: url.to_string(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+        };
+        let id = guard.id;
+        let download = download();
+        let shared = async move {
+            let _guard = guard;
+            download.await.map_err(Arc::new)
+        }.boxed().shared();
+        if let Some(weak) = shared.downgrade() {
+            in_flight.insert(url.to_string(), (id, weak));
+        }
+        Ok(shared)
+    }
+
+    fn ttl_for(&self, headers: &HeaderMap) -> Result<Option<Duration>, Error> {
+        let default_ttl = self.options.read()?.ttl;
+        let cache_control = header_str(headers, CACHE_CONTROL).unwrap_or_default().to_lowercase();
+        let mut ttl = default_ttl;
+        for directive in cache_control.split(',').map(|d| d.trim()) {
+            if directive == "no-store" {
+                return Ok(None);
+            }
+            if directive == "no-cache" {
+                ttl = Duration::ZERO;
+            } else if let Some(age) = directive.strip_prefix("max-age=") {
+                if let Ok(age) = age.trim_matches('"').parse::<u64>() {
+                    ttl = Duration::min(default_ttl, Duration::from_secs(age));
+                }
+            }
+        }
+        Ok(Some(ttl))
+    }
+}
+
+/// removes its `in_flight` entry when the download finishes or is dropped
+struct InFlightGuard {
+    cache: &'static AvatarCache,
+    url: String,
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.cache.in_flight.lock() {
+            // a newer download may have taken the url after this one lost its waiters
+            if in_flight.get(&self.url).map_or(false, |(id, _)| *id == self.id) {
+                in_flight.remove(&self.url);
+            }
+        }
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers.get(name)?.to_str().ok().map(|s| s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static CACHE: Lazy<AvatarCache> = Lazy::new(|| AvatarCache::new(AvatarCacheOptions {
+        ttl: Duration::ZERO,
+        max_size: 0,
+    }));
+
+    #[test]
+    fn dropped_download_leaves_in_flight() {
+        let first = CACHE.in_flight("a", || futures::future::pending().boxed()).unwrap();
+        let second = CACHE.in_flight("a", || unreachable!()).unwrap();
+        assert_eq!(CACHE.in_flight.lock().unwrap().len(), 1);
+        drop(first);
+        assert_eq!(CACHE.in_flight.lock().unwrap().len(), 1);
+        drop(second);
+        assert!(CACHE.in_flight.lock().unwrap().is_empty());
+    }
+}
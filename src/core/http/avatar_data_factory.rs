@@ -1,21 +1,56 @@
 use std::str::FromStr;
 
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures::FutureExt;
+
 use crate::core::builder::avatar_builder::{AvatarData, AvatarDataItem};
 use crate::core::errors::Error;
-use crate::core::http::requester::REQUESTER;
-use crate::core::http::template_data::AvatarDataURL;
+use crate::core::errors::Error::{BadRequestError, ImageDecodeError, UploadDecodeError};
+use crate::core::http::local_file::{get_local_images, resolve_local_path};
+use crate::core::http::requester::requester;
+use crate::core::http::template_data::{AvatarDataBase64, AvatarDataBlob, AvatarDataURL};
+use crate::core::loader::image_loader::decode_avatar;
 
 fn create_avatar_data_item<'a>(url_str: &Option<String>) -> Result<Option<AvatarDataItem<'a>>, Error> {
-    match url_str {
-        None => Ok(None),
-        Some(url) => {
+    let url = match url_str {
+        None => return Ok(None),
+        Some(url) => url,
+    };
+    match url_scheme(url).as_deref() {
+        Some("data") => Ok(Some(create_blob_data_item(decode_data_uri(url)?, "data uri"))),
+        None | Some("file") => Ok(Some(get_local_images(resolve_local_path(url)?))),
+        Some("http") | Some("https") => {
             let parsed_url = reqwest::Url::from_str(url)?;
-            let image = REQUESTER.get_images(parsed_url);
+            let image = requester()?.get_images(parsed_url);
             Ok(Some(image))
         }
+        Some(scheme) => Err(BadRequestError(format!("Unsupported avatar url scheme: {}", scheme))),
     }
 }
 
+/// lowercase scheme, `None` for a bare path.
+/// a single letter is a Windows drive, not a scheme
+fn url_scheme(url: &str) -> Option<String> {
+    let (scheme, _) = url.split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.');
+    if !valid || scheme.len() == 1 {
+        return None;
+    }
+    Some(scheme.to_ascii_lowercase())
+}
+
+/// decode failures are the client's, unlike those of downloaded avatars
+fn create_blob_data_item<'a>(blob: Vec<u8>, name: &'a str) -> AvatarDataItem<'a> {
+    async move {
+        decode_avatar(&blob, name).map_err(|err| match err {
+            ImageDecodeError(msg) => UploadDecodeError(msg),
+            err => err,
+        })
+    }.boxed()
+}
 
 pub fn create_avatar_data(data_url: &AvatarDataURL) -> Result<AvatarData, Error> {
     let random_vec = if data_url.random.is_none() {
@@ -29,11 +64,114 @@ pub fn create_avatar_data(data_url: &AvatarDataURL) -> Result<AvatarData, Error>
         v
     };
 
-    Ok(AvatarData {
+    let mut data = AvatarData {
         from: create_avatar_data_item(&data_url.from)?,
         to: create_avatar_data_item(&data_url.to)?,
         bot: create_avatar_data_item(&data_url.bot)?,
         group: create_avatar_data_item(&data_url.group)?,
         random: random_vec,
+        fallback: data_url.fallback.clone(),
+    };
+    if let Some(base64) = &data_url.base64 {
+        apply_blob(&mut data, decode_base64_data(base64)?);
+    }
+    Ok(data)
+}
+
+/// uploaded images replace the url of the same slot, random images are appended
+pub fn create_avatar_data_with_blob(data_url: &AvatarDataURL, blob: AvatarDataBlob) -> Result<AvatarData, Error> {
+    let mut data = create_avatar_data(data_url)?;
+    apply_blob(&mut data, blob);
+    Ok(data)
+}
+
+fn apply_blob(data: &mut AvatarData, blob: AvatarDataBlob) {
+    if let Some(b) = blob.from {
+        data.from = Some(create_blob_data_item(b, "from"));
+    }
+    if let Some(b) = blob.to {
+        data.to = Some(create_blob_data_item(b, "to"));
+    }
+    if let Some(b) = blob.bot {
+        data.bot = Some(create_blob_data_item(b, "bot"));
+    }
+    if let Some(b) = blob.group {
+        data.group = Some(create_blob_data_item(b, "group"));
+    }
+    for b in blob.random {
+        data.random.push(create_blob_data_item(b, "random"));
+    }
+}
+
+fn decode_base64(str: &str) -> Result<Vec<u8>, Error> {
+    let str: String = str.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    STANDARD.decode(str).map_err(|err|
+        BadRequestError(format!("Can not decode base64 avatar: {}", err))
+    )
+}
+
+/// `data:image/png;base64,...`
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, Error> {
+    let (header, content) = uri.split_once(',').ok_or_else(||
+        BadRequestError("Invalid data uri".to_string())
+    )?;
+    if !header.ends_with(";base64") {
+        return Err(BadRequestError("Only base64 data uri is supported".to_string()));
+    }
+    decode_base64(content)
+}
+
+fn decode_base64_data(data: &AvatarDataBase64) -> Result<AvatarDataBlob, Error> {
+    let decode = |str: &Option<String>| str.as_deref().map(decode_base64).transpose();
+    Ok(AvatarDataBlob {
+        from: decode(&data.from)?,
+        to: decode(&data.to)?,
+        bot: decode(&data.bot)?,
+        group: decode(&data.group)?,
+        random: match &data.random {
+            Some(random) => random.iter()
+                .map(|str| decode_base64(str))
+                .collect::<Result<Vec<Vec<u8>>, Error>>()?,
+            None => Vec::new(),
+        },
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheme_of_avatar_url() {
+        let cases = [
+            ("https://example.com/a.png", Some("https")),
+            ("HTTPS://example.com/a.png", Some("https")),
+            ("Http://example.com/a.png", Some("http")),
+            ("data:image/png;base64,AAAA", Some("data")),
+            ("DATA:image/png;base64,AAAA", Some("data")),
+            ("file:///avatars/a.png", Some("file")),
+            ("FILE://host/a.png", Some("file")),
+            ("ftp://example.com/a.png", Some("ftp")),
+            ("svn+ssh://example.com/a.png", Some("svn+ssh")),
+            ("avatars/a.png", None),
+            ("/avatars/a.png", None),
+            ("C:\\avatars\\a.png", None),
+            ("avatars/a:b.png", None),
+        ];
+        for (url, scheme) in cases {
+            assert_eq!(url_scheme(url).as_deref(), scheme, "{}", url);
+        }
+    }
+
+    #[test]
+    fn undecodable_upload_is_a_bad_request() {
+        let result = futures::executor::block_on(create_blob_data_item(b"not an image".to_vec(), "from"));
+        assert!(matches!(result, Err(UploadDecodeError(_))));
+    }
+
+    #[test]
+    fn unknown_scheme_is_rejected() {
+        let result = create_avatar_data_item(&Some("ftp://example.com/a.png".to_string()));
+        assert!(matches!(result, Err(BadRequestError(_))));
+    }
+}
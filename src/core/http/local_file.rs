@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+
+use crate::core::builder::avatar_builder::AvatarDataItem;
+use crate::core::errors::Error;
+use crate::core::errors::Error::{AvatarNotFoundError, BadRequestError};
+use crate::core::loader::image_loader::decode_avatar;
+
+/// local avatars are disabled until a root is set
+static LOCAL_AVATAR_ROOT: Lazy<RwLock<Option<PathBuf>>> = Lazy::new(|| RwLock::new(None));
+
+pub fn set_local_avatar_root(root: Option<&str>) -> Result<(), Error> {
+    let root = match root {
+        Some(root) => Some(std::fs::canonicalize(root)?),
+        None => None,
+    };
+    *LOCAL_AVATAR_ROOT.write()? = root;
+    Ok(())
+}
+
+/// `file://` url or bare path, relative path is resolved against the root.
+/// the resolved path must stay inside the root after following symlinks
+pub fn resolve_local_path(path_str: &str) -> Result<PathBuf, Error> {
+    let root = LOCAL_AVATAR_ROOT.read()?.clone().ok_or_else(||
+        BadRequestError("Local avatar is disabled".to_string())
+    )?;
+    let is_file_url = path_str.get(..7).map_or(false, |scheme| scheme.eq_ignore_ascii_case("file://"));
+    let path = if is_file_url {
+        reqwest::Url::parse(path_str)?.to_file_path().map_err(|_|
+            BadRequestError(format!("Invalid file url: {}", path_str))
+        )?
+    } else {
+        PathBuf::from(path_str)
+    };
+    let path = if path.is_relative() { root.join(path) } else { path };
+    let path = std::fs::canonicalize(&path).map_err(|_|
+        AvatarNotFoundError(format!("Can not find avatar file: {}", path_str))
+    )?;
+    if !path.starts_with(&root) {
+        return Err(BadRequestError(format!("Avatar path is not allowed: {}", path_str)));
+    }
+    Ok(path)
+}
+
+pub fn get_local_images<'a>(path: PathBuf) -> AvatarDataItem<'a> {
+    Box::pin(async move {
+        let blob = tokio::fs::read(&path).await?;
+        decode_avatar(blob.as_ref(), &path.to_string_lossy())
+    })
+}
@@ -1,5 +1,11 @@
 pub mod requester;
 
+pub mod local_file;
+
+pub mod avatar_cache;
+
+pub mod url_policy;
+
 pub mod avatar_data_factory;
 
 pub mod template_data;
\ No newline at end of file
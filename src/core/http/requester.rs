@@ -1,81 +1,350 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
-use std::vec;
 
+use futures::FutureExt;
+use log::{debug, warn};
 use once_cell::sync::Lazy;
-use skia_safe::{AlphaType, Codec, ColorType, Data, ImageInfo};
-use skia_safe::codec::{EncodedImageFormat, Options, ZeroInitialized};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use reqwest::redirect::Policy;
+use reqwest::{Proxy, StatusCode};
+use serde::{Deserialize, Serialize};
 
-use crate::core::builder::avatar_builder::AvatarDataItem;
+use crate::core::builder::avatar_builder::{AvatarDataItem, AvatarFrames};
+use crate::core::errors::Error;
+use crate::core::errors::Error::{AvatarTooLargeError, ContentTypeError, RequestTimeoutError};
+use crate::core::http::avatar_cache::{AVATAR_CACHE, CachedAvatar};
+use crate::core::http::url_policy::{PolicyResolver, UrlPolicy};
+use crate::core::loader::image_loader::decode_avatar_with_limit;
 
-pub struct RequesterOptions<'a> {
-    user_agent: &'a str,
-    timeout: u64,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequesterOptions {
+    #[serde(default = "user_agent_default", rename = "userAgent")]
+    pub user_agent: String,
+    /// ms
+    #[serde(default = "connect_timeout_default", rename = "connectTimeout")]
+    pub connect_timeout: u64,
+    /// ms to wait for the response head or next body chunk
+    #[serde(default = "read_timeout_default", rename = "readTimeout")]
+    pub read_timeout: u64,
+    /// `http://`, `https://` or `socks5://` proxy for all requests
+    #[serde(default = "proxy_default")]
+    pub proxy: Option<String>,
+    /// bytes
+    #[serde(default = "max_response_size_default", rename = "maxResponseSize")]
+    pub max_response_size: usize,
+    /// width * height * frame count, also applied to uploaded, base64 and local avatars
+    #[serde(default = "max_pixels_default", rename = "maxPixels")]
+    pub max_pixels: u64,
+    /// `image/png` or `image/*`, empty to allow all, missing `Content-Type` is allowed
+    #[serde(default = "allowed_content_types_default", rename = "allowedContentTypes")]
+    pub allowed_content_types: Vec<String>,
+    /// 0 to disable redirects
+    #[serde(default = "max_redirects_default", rename = "maxRedirects")]
+    pub max_redirects: usize,
+    /// extra attempts after a timeout, connection error, 404, 429 or 5xx
+    #[serde(default = "retries_default")]
+    pub retries: u32,
+    /// ms before the first retry, doubled for each next one
+    #[serde(default = "retry_backoff_default", rename = "retryBackoff")]
+    pub retry_backoff: u64,
+    /// checked for the url and every redirect
+    #[serde(default = "url_policy_default", rename = "urlPolicy")]
+    pub url_policy: UrlPolicy,
+}
+
+impl Default for RequesterOptions {
+    fn default() -> Self {
+        RequesterOptions {
+            user_agent: user_agent_default(),
+            connect_timeout: connect_timeout_default(),
+            read_timeout: read_timeout_default(),
+            proxy: proxy_default(),
+            max_response_size: max_response_size_default(),
+            max_pixels: max_pixels_default(),
+            allowed_content_types: allowed_content_types_default(),
+            max_redirects: max_redirects_default(),
+            retries: retries_default(),
+            retry_backoff: retry_backoff_default(),
+            url_policy: url_policy_default(),
+        }
+    }
 }
 
 pub struct Requester {
     client: reqwest::Client,
+    options: RequesterOptions,
 }
 
-pub(crate) static REQUESTER: Lazy<Requester> = Lazy::new(|| {
-    Requester::new(&RequesterOptions {
-        user_agent: "",
-        timeout: 60000,
-    })
+static REQUESTER: Lazy<RwLock<Arc<Requester>>> = Lazy::new(|| {
+    RwLock::new(Arc::new(
+        Requester::new(RequesterOptions::default()).expect("Can not create default requester")
+    ))
 });
 
+pub fn requester() -> Result<Arc<Requester>, Error> {
+    Ok(Arc::clone(&*REQUESTER.read()?))
+}
+
+/// replace the global requester, requests in flight keep the old one
+pub fn set_requester_options(options: RequesterOptions) -> Result<(), Error> {
+    let requester = Arc::new(Requester::new(options)?);
+    *REQUESTER.write()? = requester;
+    Ok(())
+}
+
 impl Requester {
-    pub fn new(opts: &RequesterOptions) -> Requester {
-        let client = reqwest::Client::builder()
-            .user_agent(opts.user_agent)
-            .timeout(Duration::from_millis(opts.timeout))
-            .build().ok().unwrap();
-
-        Requester {
-            client
+    pub fn max_pixels(&self) -> u64 {
+        self.options.max_pixels
+    }
+
+    pub fn new(options: RequesterOptions) -> Result<Requester, Error> {
+        let url_policy = Arc::new(options.url_policy.clone());
+        let redirect_policy = Arc::clone(&url_policy);
+        let max_redirects = options.max_redirects;
+        let mut builder = reqwest::Client::builder()
+            .user_agent(options.user_agent.clone())
+            .connect_timeout(Duration::from_millis(options.connect_timeout))
+            .redirect(Policy::custom(move |attempt| {
+                if attempt.previous().len() > max_redirects {
+                    attempt.error(format!("Too many redirects, limit is {}", max_redirects))
+                } else if let Err(err) = redirect_policy.check_url(attempt.url()) {
+                    attempt.error(err)
+                } else {
+                    attempt.follow()
+                }
+            }));
+        // the resolver would only see the proxy host, which is usually local
+        if url_policy.block_private && options.proxy.is_none() {
+            builder = builder.dns_resolver(Arc::new(PolicyResolver { policy: url_policy }));
+        }
+        if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
         }
+
+        Ok(Requester {
+            client: builder.build()?,
+            options,
+        })
     }
 
-    pub fn get_images(&self, url: reqwest::Url) -> AvatarDataItem {
+    pub fn get_images(self: Arc<Self>, url: reqwest::Url) -> AvatarDataItem<'static> {
         Box::pin(async move {
-            let time = Instant::now();
-            let blob = self.client.get(url).send().await?.bytes().await?;
-            println!("download: {:?}", time.elapsed());
-            let data = Data::new_copy(blob.as_ref());
-            let mut codec = Codec::from_data(data).unwrap();
-            let mut delay: u16 = 6;
-            let info = ImageInfo::new(
-                codec.dimensions(),
-                ColorType::RGBA8888,
-                AlphaType::Premul,
-                None,
-            );
-            let imgs = match codec.encoded_format() {
-                EncodedImageFormat::GIF => {
-                    let mut v = Vec::with_capacity(codec.get_frame_count());
-                    let mut count = 0;
-                    for i in 0..codec.get_frame_count() {
-                        let frame_info = codec.get_frame_info(i);
-                        if frame_info.is_some() {
-                            delay += frame_info.unwrap().duration as u16;
-                            count += 1;
-                        }
-                        v.push(codec.get_image(info.clone(), &Options {
-                            zero_initialized: ZeroInitialized::Yes,
-                            subset: None,
-                            frame_index: i,
-                            prior_frame: None,
-                        })?)
-                    }
-                    delay /= count;
-                    v
-                }
-                _ => {
-                    vec![codec.get_image(info, None)?]
+            self.options.url_policy.check_url(&url)?;
+            let url_str = url.to_string();
+            let cache_enabled = AVATAR_CACHE.enabled();
+            if cache_enabled {
+                if let Some(cached) = AVATAR_CACHE.get(&url_str).filter(|c| c.is_fresh()) {
+                    return Ok(cached.frames);
                 }
-            };
+            }
 
-            Ok((Arc::new(imgs), delay))
+            // concurrent requests for the same url share one download and its result
+            let download = AVATAR_CACHE.in_flight(&url_str, || {
+                let cache_key = url_str.clone();
+                async move {
+                    // filled by a download that finished since the check above
+                    match AVATAR_CACHE.get(&cache_key).filter(|_| cache_enabled) {
+                        Some(cached) if cached.is_fresh() => Ok(cached.frames),
+                        cached => self.download_with_retry(url, cached).await,
+                    }
+                }.boxed()
+            })?;
+            download.await.map_err(Error::SharedError)
         })
     }
+
+    async fn download_with_retry(&self, url: reqwest::Url, cached: Option<CachedAvatar>) -> Result<AvatarFrames, Error> {
+        let mut attempt = 0;
+        loop {
+            match self.download(url.clone(), cached.clone()).await {
+                Err(err) if attempt < self.options.retries && is_retryable(&err) => {
+                    let backoff = self.options.retry_backoff.saturating_mul(1 << attempt.min(16));
+                    warn!("download {} failed, retry in {}ms: {}", url, backoff, err);
+                    tokio::time::sleep(Duration::from_millis(backoff)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// conditional request if a stale entry is given, result is written to `AVATAR_CACHE`
+    async fn download(&self, url: reqwest::Url, cached: Option<CachedAvatar>) -> Result<AvatarFrames, Error> {
+        let time = Instant::now();
+        let url_str = url.to_string();
+        let mut request = self.client.get(url);
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = self.with_read_timeout(&url_str, request.send()).await??;
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+            AVATAR_CACHE.refresh(&url_str, response.headers())?;
+            return Ok(cached.frames);
+        }
+
+        let mut response = response.error_for_status()?;
+        let headers = response.headers().clone();
+        self.check_headers(&url_str, &headers)?;
+        let mut blob: Vec<u8> = Vec::new();
+        while let Some(chunk) = self.with_read_timeout(&url_str, response.chunk()).await?? {
+            if blob.len() + chunk.len() > self.options.max_response_size {
+                return Err(self.too_large(&url_str));
+            }
+            blob.extend_from_slice(&chunk);
+        }
+        debug!("download {}: {:?}", &url_str, time.elapsed());
+        let frames = decode_avatar_with_limit(&blob, &url_str, self.options.max_pixels)?;
+        if AVATAR_CACHE.enabled() {
+            AVATAR_CACHE.insert(&url_str, frames.clone(), &headers)?;
+        }
+        Ok(frames)
+    }
+
+    fn check_headers(&self, url_str: &str, headers: &HeaderMap) -> Result<(), Error> {
+        let length = headers.get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if length.map_or(false, |length| length > self.options.max_response_size) {
+            return Err(self.too_large(url_str));
+        }
+
+        let allowed = &self.options.allowed_content_types;
+        let content_type = headers.get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(';').next().unwrap_or_default().trim().to_lowercase());
+        if let Some(content_type) = content_type {
+            if !allowed.is_empty() && !allowed.iter().any(|a| content_type_matches(a, &content_type)) {
+                return Err(ContentTypeError(format!("{} returned {}", url_str, content_type)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn with_read_timeout<T>(&self, url_str: &str, future: impl std::future::Future<Output=T>) -> Result<T, Error> {
+        tokio::time::timeout(Duration::from_millis(self.options.read_timeout), future).await
+            .map_err(|_| RequestTimeoutError(format!("{} did not respond in {}ms", url_str, self.options.read_timeout)))
+    }
+
+    fn too_large(&self, url_str: &str) -> Error {
+        AvatarTooLargeError(format!(
+            "{} is larger than {} bytes", url_str, self.options.max_response_size
+        ))
+    }
+}
+
+fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::RequestTimeoutError(_) => true,
+        Error::RequestError(err) => err.is_timeout() || err.is_connect() || err.status().map_or(
+            false,
+            |status| status.is_server_error()
+                || status == StatusCode::NOT_FOUND
+                || status == StatusCode::TOO_MANY_REQUESTS,
+        ),
+        _ => false,
+    }
+}
+
+fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => content_type.split('/').next() == Some(prefix),
+        None => pattern == content_type,
+    }
+}
+
+fn user_agent_default() -> String {
+    "".to_string()
+}
+
+fn connect_timeout_default() -> u64 {
+    10000
+}
+
+fn read_timeout_default() -> u64 {
+    60000
+}
+
+fn proxy_default() -> Option<String> {
+    None
+}
+
+fn max_response_size_default() -> usize {
+    16 * 1024 * 1024
+}
+
+fn max_pixels_default() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn allowed_content_types_default() -> Vec<String> {
+    vec!["image/*".to_string(), "application/octet-stream".to_string()]
+}
+
+fn max_redirects_default() -> usize {
+    10
+}
+
+fn retries_default() -> u32 {
+    2
+}
+
+fn retry_backoff_default() -> u64 {
+    200
+}
+
+fn url_policy_default() -> UrlPolicy {
+    UrlPolicy::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_failures_share_one_download() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server_hits = Arc::clone(&hits);
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                server_hits.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = socket.read(&mut buf).await;
+                    // long enough for every request to join the first download
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let _ = socket.write_all(
+                        b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    ).await;
+                });
+            }
+        });
+
+        let requester = Arc::new(Requester::new(RequesterOptions {
+            retries: 0,
+            url_policy: UrlPolicy {
+                block_private: false,
+                ..UrlPolicy::default()
+            },
+            ..RequesterOptions::default()
+        }).unwrap());
+        let url = reqwest::Url::parse(&format!("http://{}/avatar.png", addr)).unwrap();
+        let results = futures::future::join_all(
+            (0..8).map(|_| Arc::clone(&requester).get_images(url.clone()))
+        ).await;
+        assert!(results.iter().all(|result| result.is_err()));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
 }
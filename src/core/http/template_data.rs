@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::core::encoder::encoder::EncodeOptions;
+use crate::core::template::avatar_template::AvatarFallback;
 use crate::core::template::text_template::TextData;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -8,6 +10,8 @@ pub struct PetpetData {
     pub avatar: AvatarDataURL,
     #[serde(default = "TextData::default")]
     pub text: TextData,
+    #[serde(flatten)]
+    pub encode: EncodeOptions,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -17,6 +21,12 @@ pub struct AvatarDataURL {
     pub bot: Option<String>,
     pub group: Option<String>,
     pub random: Option<Vec<String>>,
+    /// plain base64 images, take precedence over urls
+    #[serde(default = "base64_default")]
+    pub base64: Option<AvatarDataBase64>,
+    /// overrides `fallback` of the template
+    #[serde(default = "fallback_default")]
+    pub fallback: Option<Vec<AvatarFallback>>,
 }
 
 impl Default for AvatarDataURL {
@@ -27,6 +37,35 @@ impl Default for AvatarDataURL {
             bot: None,
             group: None,
             random: None,
+            base64: base64_default(),
+            fallback: fallback_default(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AvatarDataBase64 {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub bot: Option<String>,
+    pub group: Option<String>,
+    pub random: Option<Vec<String>>,
+}
+
+fn base64_default() -> Option<AvatarDataBase64> {
+    None
+}
+
+fn fallback_default() -> Option<Vec<AvatarFallback>> {
+    None
+}
+
+/// encoded avatar images already in memory, take precedence over urls
+#[derive(Debug, Clone, Default)]
+pub struct AvatarDataBlob {
+    pub from: Option<Vec<u8>>,
+    pub to: Option<Vec<u8>>,
+    pub bot: Option<Vec<u8>>,
+    pub group: Option<Vec<u8>>,
+    pub random: Vec<Vec<u8>>,
+}
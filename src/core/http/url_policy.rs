@@ -0,0 +1,264 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::core::errors::Error;
+use crate::core::errors::Error::UrlPolicyError;
+
+/// which avatar urls the requester may fetch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UrlPolicy {
+    #[serde(default = "allowed_schemes_default", rename = "allowedSchemes")]
+    pub allowed_schemes: Vec<String>,
+    /// `example.com` or `*.example.com`, empty to allow all hosts
+    #[serde(default = "hosts_default", rename = "allowedHosts")]
+    pub allowed_hosts: Vec<String>,
+    #[serde(default = "hosts_default", rename = "deniedHosts")]
+    pub denied_hosts: Vec<String>,
+    /// reject loopback, private, link-local and other non-public addresses after DNS resolution.
+    /// behind `proxy` the proxy resolves hosts, so only ip literals are checked
+    #[serde(default = "block_private_default", rename = "blockPrivate")]
+    pub block_private: bool,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        UrlPolicy {
+            allowed_schemes: allowed_schemes_default(),
+            allowed_hosts: hosts_default(),
+            denied_hosts: hosts_default(),
+            block_private: block_private_default(),
+        }
+    }
+}
+
+impl UrlPolicy {
+    /// checks scheme, host lists and ip literal, does not resolve
+    pub fn check_url(&self, url: &Url) -> Result<(), Error> {
+        if !self.allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(url.scheme())) {
+            return Err(UrlPolicyError(format!("Scheme is not allowed: {}", url)));
+        }
+        let host = url.host_str()
+            .ok_or_else(|| UrlPolicyError(format!("Missing host: {}", url)))?
+            .to_lowercase();
+        if self.denied_hosts.iter().any(|pattern| host_matches(pattern, &host)) {
+            return Err(UrlPolicyError(format!("Host is denied: {}", host)));
+        }
+        if !self.allowed_hosts.is_empty()
+            && !self.allowed_hosts.iter().any(|pattern| host_matches(pattern, &host)) {
+            return Err(UrlPolicyError(format!("Host is not allowed: {}", host)));
+        }
+        let ip = match url.host() {
+            Some(url::Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+            _ => None,
+        };
+        if let Some(ip) = ip {
+            self.check_ip(&host, ip)?;
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), Error> {
+        if self.block_private && !is_public_ip(ip) {
+            return Err(UrlPolicyError(format!("{} resolves to non-public address {}", host, ip)));
+        }
+        Ok(())
+    }
+}
+
+/// DNS resolver dropping blocked addresses,
+/// covers redirects and hosts resolving differently between check and connect
+pub struct PolicyResolver {
+    pub policy: Arc<UrlPolicy>,
+}
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = Arc::clone(&self.policy);
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?
+                .filter(|addr| policy.check_ip(&host, addr.ip()).is_ok())
+                .collect();
+            if addrs.is_empty() {
+                return Err(UrlPolicyError(format!("{} has no allowed address", host)).into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => pattern == host,
+    }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8
+        || a == 0
+        // shared address space 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // benchmarking 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ipv4) = embedded_ipv4(ip) {
+        return is_public_ipv4(ipv4);
+    }
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // deprecated site-local fec0::/10
+        || (first & 0xffc0) == 0xfec0
+        // documentation 2001:db8::/32
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// IPv4 address routed to by NAT64, 6to4 or the deprecated IPv4-compatible form
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let octets = ip.octets();
+    match segments {
+        // NAT64 well-known prefix 64:ff9b::/96
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])),
+        // 6to4 2002::/16, IPv4 in the next 32 bits
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        // IPv4-compatible ::a.b.c.d, `::` and `::1` end up as 0.0.0.0/8
+        [0, 0, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])),
+        _ => None,
+    }
+}
+
+fn allowed_schemes_default() -> Vec<String> {
+    vec!["http".to_string(), "https".to_string()]
+}
+
+fn hosts_default() -> Vec<String> {
+    Vec::new()
+}
+
+fn block_private_default() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(url: &str) -> bool {
+        UrlPolicy::default().check_url(&Url::parse(url).unwrap()).is_ok()
+    }
+
+    #[test]
+    fn check_url_table() {
+        let cases = [
+            ("https://example.com/a.png", true),
+            ("http://93.184.216.34/a.png", true),
+            ("http://[2606:2800:220:1::1]/a.png", true),
+            // scheme allow-list
+            ("ftp://example.com/a.png", false),
+            ("file:///etc/passwd", false),
+            // loopback
+            ("http://127.0.0.1/", false),
+            ("http://127.1.2.3/", false),
+            ("http://[::1]/", false),
+            // private
+            ("http://10.0.0.1/", false),
+            ("http://192.168.1.1/", false),
+            ("http://172.16.0.1/", false),
+            ("http://[fd00::1]/", false),
+            // link-local
+            ("http://169.254.169.254/", false),
+            ("http://[fe80::1]/", false),
+            // CGNAT
+            ("http://100.64.0.1/", false),
+            ("http://100.127.255.255/", false),
+            ("http://100.128.0.1/", true),
+            // IPv4-mapped IPv6
+            ("http://[::ffff:127.0.0.1]/", false),
+            ("http://[::ffff:10.0.0.1]/", false),
+            ("http://[::ffff:93.184.216.34]/", true),
+            // unspecified
+            ("http://0.0.0.0/", false),
+            ("http://[::]/", false),
+            // NAT64
+            ("http://[64:ff9b::7f00:1]/", false),
+            ("http://[64:ff9b::a00:1]/", false),
+            ("http://[64:ff9b::5db8:d822]/", true),
+            // 6to4
+            ("http://[2002:7f00:1::1]/", false),
+            ("http://[2002:c0a8:101::1]/", false),
+            ("http://[2002:5db8:d822::1]/", true),
+            // IPv4-compatible
+            ("http://[::127.0.0.1]/", false),
+            ("http://[::10.0.0.1]/", false),
+            ("http://[::93.184.216.34]/", true),
+            // site-local
+            ("http://[fec0::1]/", false),
+            ("http://[feff::1]/", false),
+        ];
+        for (url, allowed) in cases {
+            assert_eq!(check(url), allowed, "{}", url);
+        }
+    }
+
+    #[test]
+    fn check_url_hosts() {
+        let policy = UrlPolicy {
+            allowed_hosts: vec!["*.example.com".to_string(), "example.org".to_string()],
+            denied_hosts: vec!["bad.example.com".to_string()],
+            ..UrlPolicy::default()
+        };
+        let check = |url: &str| policy.check_url(&Url::parse(url).unwrap()).is_ok();
+        assert!(check("https://img.example.com/a.png"));
+        assert!(check("https://EXAMPLE.org/a.png"));
+        assert!(!check("https://example.com/a.png"));
+        assert!(!check("https://bad.example.com/a.png"));
+        assert!(!check("https://example.net/a.png"));
+    }
+
+    #[test]
+    fn block_private_disabled() {
+        let policy = UrlPolicy {
+            block_private: false,
+            ..UrlPolicy::default()
+        };
+        assert!(policy.check_url(&Url::parse("http://127.0.0.1/").unwrap()).is_ok());
+        assert!(policy.check_url(&Url::parse("ftp://127.0.0.1/").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use skia_safe::{AlphaType, ColorType, Data, Image, ImageInfo, images};
+
+use crate::core::errors::Error;
+use crate::core::errors::Error::{AvatarTooLargeError, ImageDecodeError};
+
+/// composited frames and their durations in ms
+pub type DecodedAnimation = (Vec<Image>, Vec<u32>);
+
+/// animated WebP through libwebp's demuxer, frames come out already composited.
+/// `None` if the image is not animated
+pub fn decode_webp_animation(blob: &[u8], source: &str) -> Result<Option<DecodedAnimation>, Error> {
+    // VP8X header with the animation flag, skips decoding still images twice
+    if blob.len() < 21 || &blob[12..16] != b"VP8X" || blob[20] & 0x02 == 0 {
+        return Ok(None);
+    }
+    let decoded = webp::AnimDecoder::new(blob).decode()
+        .map_err(|err| ImageDecodeError(format!("Can not decode WebP {}: {}", source, err)))?;
+    if !decoded.has_animation() || decoded.len() <= 1 {
+        return Ok(None);
+    }
+
+    let mut frames = Vec::with_capacity(decoded.len());
+    let mut durations = Vec::with_capacity(decoded.len());
+    // timestamps are the end time of each frame
+    let mut last_time = 0;
+    for frame in decoded.into_iter() {
+        let rgba = match frame.get_layout() {
+            webp::PixelLayout::Rgba => frame.get_image().to_vec(),
+            webp::PixelLayout::Rgb => to_rgba(frame.get_image(), 3),
+        };
+        frames.push(rgba_image(rgba, frame.width(), frame.height(), source)?);
+        durations.push(frame_duration(frame.get_time_ms() - last_time));
+        last_time = frame.get_time_ms();
+    }
+    Ok(Some((frames, durations)))
+}
+
+/// APNG following the `fcTL` dispose and blend ops, frames over `max_pixels` are sampled.
+/// `None` if the PNG has no animation control chunk
+pub fn decode_apng(blob: &[u8], source: &str, max_pixels: u64) -> Result<Option<DecodedAnimation>, Error> {
+    let map_err = |err: png::DecodingError| ImageDecodeError(format!("Can not decode APNG {}: {}", source, err));
+    let mut decoder = png::Decoder::new(blob);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(map_err)?;
+
+    let info = reader.info();
+    let frame_count = match &info.animation_control {
+        Some(control) if control.num_frames > 1 => control.num_frames as usize,
+        _ => return Ok(None),
+    };
+    let (width, height) = (info.width as usize, info.height as usize);
+    let frame_pixels = u64::max(1, width as u64 * height as u64);
+    let kept_count = usize::min(frame_count, (max_pixels / frame_pixels) as usize);
+    if kept_count == 0 {
+        return Err(AvatarTooLargeError(format!(
+            "{} has {} pixels, limit is {}", source, frame_pixels * frame_count as u64, max_pixels
+        )));
+    }
+    let keep = sampled_frames(frame_count, kept_count);
+    let skip_default = info.frame_control.is_none();
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    // the default image is not a frame if no fcTL comes before IDAT
+    if skip_default {
+        reader.next_frame(&mut buf).map_err(map_err)?;
+    }
+    let mut canvas = vec![0u8; width * height * 4];
+    let mut frames = Vec::with_capacity(kept_count);
+    let mut durations: Vec<u32> = Vec::with_capacity(kept_count);
+    for index in 0..frame_count {
+        let output = reader.next_frame(&mut buf).map_err(map_err)?;
+        let control = reader.info().frame_control.ok_or_else(||
+            ImageDecodeError(format!("Missing frame control in {}", source))
+        )?;
+        let rgba = match output.color_type {
+            png::ColorType::Rgba => buf[..output.buffer_size()].to_vec(),
+            png::ColorType::Rgb => to_rgba(&buf[..output.buffer_size()], 3),
+            png::ColorType::GrayscaleAlpha => gray_alpha_to_rgba(&buf[..output.buffer_size()]),
+            _ => gray_to_rgba(&buf[..output.buffer_size()]),
+        };
+
+        let region = (
+            control.x_offset as usize,
+            control.y_offset as usize,
+            output.width as usize,
+            output.height as usize,
+        );
+        if region.0 + region.2 > width || region.1 + region.3 > height {
+            return Err(ImageDecodeError(format!("Frame out of bounds in {}", source)));
+        }
+        let previous = match control.dispose_op {
+            png::DisposeOp::Previous if index > 0 => Some(canvas.clone()),
+            _ => None,
+        };
+        blend_region(&mut canvas, width, &rgba, region, control.blend_op == png::BlendOp::Over);
+
+        let den = if control.delay_den == 0 { 100 } else { control.delay_den as u32 };
+        let duration = frame_duration((control.delay_num as u32 * 1000 / den) as i32);
+        if keep[index] {
+            frames.push(rgba_image(canvas.clone(), width as u32, height as u32, source)?);
+            durations.push(duration);
+        } else if let Some(last) = durations.last_mut() {
+            // a skipped frame extends the kept one before it
+            *last += duration;
+        }
+
+        match control.dispose_op {
+            png::DisposeOp::None => {}
+            // disposing the first frame to previous is the same as to background
+            png::DisposeOp::Previous if previous.is_some() => canvas = previous.unwrap(),
+            _ => clear_region(&mut canvas, width, region),
+        }
+    }
+    Ok(Some((frames, durations)))
+}
+
+/// whether each frame is kept when spreading `max_frames` evenly over `frame_count`,
+/// the same frames `FrameLimitStrategy::SAMPLE` picks
+pub fn sampled_frames(frame_count: usize, max_frames: usize) -> Vec<bool> {
+    let kept_count = usize::min(frame_count, max_frames);
+    let mut keep = vec![false; frame_count];
+    for i in 0..kept_count {
+        keep[i * frame_count / kept_count] = true;
+    }
+    keep
+}
+
+/// ms, browsers treat tiny frame delays as 100ms
+pub fn frame_duration(duration: i32) -> u32 {
+    if duration <= 10 { 100 } else { duration as u32 }
+}
+
+fn rgba_image(pixels: Vec<u8>, width: u32, height: u32, source: &str) -> Result<Image, Error> {
+    let info = ImageInfo::new(
+        (width as i32, height as i32),
+        ColorType::RGBA8888,
+        AlphaType::Unpremul,
+        None,
+    );
+    let row_bytes = info.min_row_bytes();
+    images::raster_from_data(&info, Data::new_copy(&pixels), row_bytes).ok_or_else(||
+        ImageDecodeError(format!("Can not create frame of {}", source))
+    )
+}
+
+fn blend_region(
+    canvas: &mut [u8],
+    canvas_width: usize,
+    frame: &[u8],
+    (x, y, w, h): (usize, usize, usize, usize),
+    over: bool,
+) {
+    for row in 0..h {
+        let dst_start = ((y + row) * canvas_width + x) * 4;
+        let dst = &mut canvas[dst_start..dst_start + w * 4];
+        let src = &frame[row * w * 4..(row + 1) * w * 4];
+        if !over {
+            dst.copy_from_slice(src);
+            continue;
+        }
+        for (d, s) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+            let sa = s[3] as f32 / 255.0;
+            let da = d[3] as f32 / 255.0;
+            let out_a = sa + da * (1.0 - sa);
+            if out_a <= 0.0 {
+                d.copy_from_slice(&[0, 0, 0, 0]);
+                continue;
+            }
+            for c in 0..3 {
+                d[c] = ((s[c] as f32 * sa + d[c] as f32 * da * (1.0 - sa)) / out_a).round() as u8;
+            }
+            d[3] = (out_a * 255.0).round() as u8;
+        }
+    }
+}
+
+fn clear_region(canvas: &mut [u8], canvas_width: usize, (x, y, w, h): (usize, usize, usize, usize)) {
+    for row in 0..h {
+        let start = ((y + row) * canvas_width + x) * 4;
+        canvas[start..start + w * 4].fill(0);
+    }
+}
+
+fn to_rgba(pixels: &[u8], channels: usize) -> Vec<u8> {
+    pixels.chunks_exact(channels)
+        .flat_map(|p| [p[0], p[1], p[2], 0xff])
+        .collect()
+}
+
+fn gray_to_rgba(pixels: &[u8]) -> Vec<u8> {
+    pixels.iter().flat_map(|g| [*g, *g, *g, 0xff]).collect()
+}
+
+fn gray_alpha_to_rgba(pixels: &[u8]) -> Vec<u8> {
+    pixels.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect()
+}
@@ -0,0 +1,149 @@
+use std::sync::{Arc, RwLock};
+
+use once_cell::sync::Lazy;
+
+use crate::core::builder::avatar_builder::AvatarFrames;
+use crate::core::errors::Error;
+use crate::core::template::avatar_template::{AvatarFrameLimit, FrameLimitStrategy};
+
+static FRAME_LIMIT: Lazy<RwLock<AvatarFrameLimit>> = Lazy::new(|| RwLock::new(frame_limit_default()));
+
+pub fn frame_limit_default() -> AvatarFrameLimit {
+    AvatarFrameLimit {
+        max_frames: Some(100),
+        max_duration: Some(20_000),
+        max_pixels: Some(64 * 1024 * 1024),
+        strategy: Some(FrameLimitStrategy::SAMPLE),
+    }
+}
+
+/// used for fields a template does not set
+pub fn set_frame_limit(limit: AvatarFrameLimit) -> Result<(), Error> {
+    *FRAME_LIMIT.write()? = limit;
+    Ok(())
+}
+
+/// cut an animated avatar down to the template limit merged with the global one
+pub fn limit_frames(frames: AvatarFrames, template_limit: &AvatarFrameLimit) -> Result<AvatarFrames, Error> {
+    let (images, durations) = frames;
+    if images.len() <= 1 {
+        return Ok((images, durations));
+    }
+    let limit = template_limit.or(&FRAME_LIMIT.read()?);
+
+    // duration is cut first so sampling spreads over the remaining part
+    let mut length = images.len();
+    if let Some(max_duration) = limit.max_duration {
+        let mut total = 0;
+        length = durations.iter()
+            .take_while(|duration| {
+                total += **duration;
+                total <= max_duration
+            })
+            .count()
+            .max(1);
+    }
+
+    let (width, height) = (images[0].width() as u64, images[0].height() as u64);
+    let mut max_frames = limit.max_frames.unwrap_or(usize::MAX);
+    if let Some(max_pixels) = limit.max_pixels {
+        let frame_pixels = u64::max(1, width * height);
+        max_frames = usize::min(max_frames, (max_pixels / frame_pixels) as usize);
+    }
+    let max_frames = usize::max(1, max_frames);
+
+    if length <= max_frames {
+        if length == images.len() {
+            return Ok((images, durations));
+        }
+        return Ok((Arc::new(images[..length].to_vec()), durations[..length].to_vec()));
+    }
+
+    match limit.strategy.unwrap_or(FrameLimitStrategy::SAMPLE) {
+        FrameLimitStrategy::TRUNCATE => Ok((
+            Arc::new(images[..max_frames].to_vec()),
+            durations[..max_frames].to_vec(),
+        )),
+        FrameLimitStrategy::SAMPLE => {
+            let starts: Vec<usize> = (0..max_frames).map(|i| i * length / max_frames).collect();
+            let sampled = starts.iter().map(|start| images[*start].clone()).collect();
+            let sampled_durations = starts.iter().enumerate().map(|(i, start)| {
+                let end = starts.get(i + 1).copied().unwrap_or(length);
+                durations[*start..end].iter().sum()
+            }).collect();
+            Ok((Arc::new(sampled), sampled_durations))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use skia_safe::{Image, surfaces};
+
+    use super::*;
+
+    fn frames(count: usize, size: i32, duration: u32) -> AvatarFrames {
+        let image: Image = surfaces::raster_n32_premul((size, size)).unwrap().image_snapshot();
+        (Arc::new(vec![image; count]), vec![duration; count])
+    }
+
+    fn limit(
+        max_frames: Option<usize>,
+        max_duration: Option<u32>,
+        max_pixels: Option<u64>,
+        strategy: Option<FrameLimitStrategy>,
+    ) -> AvatarFrameLimit {
+        AvatarFrameLimit { max_frames, max_duration, max_pixels, strategy }
+    }
+
+    #[test]
+    fn within_limit() {
+        let (images, durations) = limit_frames(frames(10, 8, 100), &limit(None, None, None, None)).unwrap();
+        assert_eq!(images.len(), 10);
+        assert_eq!(durations, vec![100; 10]);
+    }
+
+    #[test]
+    fn single_frame() {
+        let limit = limit(Some(1), Some(1), Some(1), None);
+        let (images, durations) = limit_frames(frames(1, 8, 100), &limit).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(durations, vec![100]);
+    }
+
+    #[test]
+    fn max_duration() {
+        let (images, durations) = limit_frames(frames(10, 8, 100), &limit(None, Some(450), None, None)).unwrap();
+        assert_eq!(images.len(), 4);
+        assert_eq!(durations, vec![100; 4]);
+        // the first frame is kept even if longer than the limit
+        let (images, _) = limit_frames(frames(10, 8, 100), &limit(None, Some(50), None, None)).unwrap();
+        assert_eq!(images.len(), 1);
+    }
+
+    #[test]
+    fn sample_keeps_total_duration() {
+        let limit = limit(Some(4), None, None, Some(FrameLimitStrategy::SAMPLE));
+        let (images, durations) = limit_frames(frames(10, 8, 100), &limit).unwrap();
+        assert_eq!(images.len(), 4);
+        assert_eq!(durations, vec![200, 300, 200, 300]);
+    }
+
+    #[test]
+    fn truncate() {
+        let limit = limit(Some(3), None, None, Some(FrameLimitStrategy::TRUNCATE));
+        let (images, durations) = limit_frames(frames(10, 8, 100), &limit).unwrap();
+        assert_eq!(images.len(), 3);
+        assert_eq!(durations, vec![100; 3]);
+    }
+
+    #[test]
+    fn max_pixels() {
+        let limit = limit(None, None, Some(10 * 10 * 3 + 1), Some(FrameLimitStrategy::TRUNCATE));
+        let (images, _) = limit_frames(frames(10, 10, 100), &limit).unwrap();
+        assert_eq!(images.len(), 3);
+        let limit = AvatarFrameLimit { max_pixels: Some(1), ..limit };
+        let (images, _) = limit_frames(frames(10, 10, 100), &limit).unwrap();
+        assert_eq!(images.len(), 1);
+    }
+}
@@ -1,15 +1,20 @@
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use once_cell::sync::Lazy;
 use schnellru::{ByLength, LruMap};
-use skia_safe::{AlphaType, Codec, ColorType, Data, Image, ImageInfo};
+use skia_safe::{AlphaType, codec, Codec, ColorType, Data, Image, ImageInfo, images};
+use skia_safe::codec::{EncodedImageFormat, Options, ZeroInitialized};
 
-use crate::core::errors::Error::{self, FileError, ImageDecodeError};
+use crate::core::builder::avatar_builder::AvatarFrames;
+use crate::core::errors::Error::{self, AvatarTooLargeError, FileError, ImageDecodeError};
+use crate::core::http::requester::requester;
+use crate::core::loader::animation::{decode_apng, decode_webp_animation, frame_duration, sampled_frames};
 
 static MAX_CACHE_LENGTH: Lazy<u32> = Lazy::new(|| 32);
 
-static mut IMAGE_CACHE: Lazy<LruMap<String, Vec<Image>, ByLength>> = Lazy::new(|| {
-    LruMap::new(ByLength::new(*MAX_CACHE_LENGTH))
+static IMAGE_CACHE: Lazy<Mutex<LruMap<String, Arc<Vec<Image>>, ByLength>>> = Lazy::new(|| {
+    Mutex::new(LruMap::new(ByLength::new(*MAX_CACHE_LENGTH)))
 });
 
 pub fn has_image(path: &str) -> bool {
@@ -37,10 +42,18 @@ pub fn image_count(path: &str) -> usize {
         .count()
 }
 
+/// size of `0.png` without decoding the pixels
+pub fn image_size(path: &str) -> Option<(i32, i32)> {
+    let blob = std::fs::read(format!("{}/0.png", path)).ok()?;
+    let codec = Codec::from_data(Data::new_copy(blob.as_ref()))?;
+    let size = codec.dimensions();
+    Some((size.width, size.height))
+}
+
 pub fn load_image(path: String) -> Result<Image, Error> {
     if let Ok(blob) = std::fs::read(&path) {
         let data = Data::new_copy(blob.as_ref());
-        let mut codec = Codec::from_data(data).unwrap();
+        let mut codec = Codec::from_data(data).ok_or_else(|| ImageDecodeError(path.clone()))?;
         let info = ImageInfo::new(
             codec.dimensions(),
             ColorType::RGBA8888,
@@ -53,6 +66,109 @@ pub fn load_image(path: String) -> Result<Image, Error> {
     }
 }
 
+/// decode avatar from encoded bytes, GIF frames included, limited by the requester `maxPixels`.
+/// `source` is only used in error message
+pub fn decode_avatar(blob: &[u8], source: &str) -> Result<AvatarFrames, Error> {
+    decode_avatar_with_limit(blob, source, requester()?.max_pixels())
+}
+
+/// `max_pixels` limits width * height * frame count, checked before decoding.
+/// GIF and APNG frames over the limit are sampled evenly instead of rejected
+pub fn decode_avatar_with_limit(blob: &[u8], source: &str, max_pixels: u64) -> Result<AvatarFrames, Error> {
+    let data = Data::new_copy(blob);
+    let mut codec = Codec::from_data(data).ok_or_else(||
+        ImageDecodeError(format!("Can not decode avatar: {}", source))
+    )?;
+    let size = codec.dimensions();
+    let frame_count = usize::max(1, codec.get_frame_count());
+    let frame_pixels = u64::max(1, size.width as u64 * size.height as u64);
+    // libwebp decodes every frame at once, so WebP can not be sampled while decoding
+    let decoded_count = match codec.encoded_format() {
+        EncodedImageFormat::WEBP => frame_count,
+        _ => usize::min(frame_count, (max_pixels / frame_pixels) as usize),
+    };
+    if decoded_count == 0 || frame_pixels * decoded_count as u64 > max_pixels {
+        return Err(AvatarTooLargeError(format!(
+            "{} has {} pixels, limit is {}", source, frame_pixels * frame_count as u64, max_pixels
+        )));
+    }
+    let info = ImageInfo::new(
+        size,
+        ColorType::RGBA8888,
+        AlphaType::Premul,
+        None,
+    );
+    // skia decodes APNG as a still image and its animated WebP support depends on the build
+    let animation = match codec.encoded_format() {
+        EncodedImageFormat::WEBP => decode_webp_animation(blob, source)?,
+        EncodedImageFormat::PNG => decode_apng(blob, source, max_pixels)?,
+        _ => None,
+    };
+    if let Some((imgs, durations)) = animation {
+        return Ok((Arc::new(imgs), durations));
+    }
+    if frame_count <= 1 {
+        return Ok((Arc::new(vec![codec.get_image(info, None)?]), vec![0]));
+    }
+
+    // frames depending on a previous one are decoded on top of its pixels,
+    // the codec applies disposal of the required frame
+    let prior_frames: Vec<Option<usize>> = (0..frame_count)
+        .map(|i| {
+            let frame_info = codec.get_frame_info(i).unwrap_or_default();
+            usize::try_from(frame_info.required_frame).ok().filter(|prior| *prior < i)
+        })
+        .collect();
+    // pixels of a frame are kept until the last frame decoded on top of them
+    let mut last_use = vec![0; frame_count];
+    for (i, prior) in prior_frames.iter().enumerate() {
+        if let Some(prior) = prior {
+            last_use[*prior] = i;
+        }
+    }
+    let keep = sampled_frames(frame_count, decoded_count);
+
+    let row_bytes = info.min_row_bytes();
+    let byte_size = info.compute_byte_size(row_bytes);
+    let mut buffers: Vec<Option<Data>> = (0..frame_count).map(|_| None).collect();
+    let mut imgs = Vec::with_capacity(decoded_count);
+    let mut durations: Vec<u32> = Vec::with_capacity(decoded_count);
+    for (i, prior_frame) in prior_frames.iter().copied().enumerate() {
+        let mut pixels = match prior_frame.and_then(|prior| buffers[prior].as_ref()) {
+            Some(prior) => prior.as_bytes().to_vec(),
+            None => vec![0; byte_size],
+        };
+        let result = codec.get_pixels_with_options(&info, &mut pixels, row_bytes, Some(&Options {
+            zero_initialized: if prior_frame.is_some() { ZeroInitialized::No } else { ZeroInitialized::Yes },
+            subset: None,
+            frame_index: i,
+            prior_frame,
+        }));
+        if result != codec::Result::Success && result != codec::Result::IncompleteInput {
+            return Err(ImageDecodeError(format!("Can not decode frame {} of {}: {:?}", i, source, result)));
+        }
+        if let Some(prior) = prior_frame.filter(|prior| last_use[*prior] == i) {
+            buffers[prior] = None;
+        }
+
+        let pixels = Data::new_copy(&pixels);
+        let duration = frame_duration(codec.get_frame_info(i).unwrap_or_default().duration);
+        if keep[i] {
+            imgs.push(images::raster_from_data(&info, pixels.clone(), row_bytes).ok_or_else(||
+                ImageDecodeError(format!("Can not create frame of {}", source))
+            )?);
+            durations.push(duration);
+        } else if let Some(last) = durations.last_mut() {
+            // a skipped frame extends the kept one before it
+            *last += duration;
+        }
+        if last_use[i] > i {
+            buffers[i] = Some(pixels);
+        }
+    }
+    Ok((Arc::new(imgs), durations))
+}
+
 fn load_background(path: &str) -> Result<Vec<Image>, Error> {
     let mut images: Vec<Image> = Vec::new();
     for i in 0.. {
@@ -69,15 +185,69 @@ fn load_background(path: &str) -> Result<Vec<Image>, Error> {
     Ok(images)
 }
 
-pub fn load_cached_background(path: &str) -> Result<&Vec<Image>, Error> {
-    unsafe {
-        Ok(match IMAGE_CACHE.get(path) {
-            None => {
-                let img = load_background(path)?;
-                IMAGE_CACHE.insert(path.parse().unwrap(), img);
-                IMAGE_CACHE.get(path).unwrap()
+pub fn load_cached_background(path: &str) -> Result<Arc<Vec<Image>>, Error> {
+    if let Some(images) = IMAGE_CACHE.lock()?.get(path) {
+        return Ok(Arc::clone(images));
+    }
+    let images = Arc::new(load_background(path)?);
+    IMAGE_CACHE.lock()?.insert(path.to_string(), Arc::clone(&images));
+    Ok(images)
+}
+
+pub fn invalidate_cached_background(path: &str) -> Result<(), Error> {
+    IMAGE_CACHE.lock()?.remove(path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `size` x `size`, `delay` in 1/100 s
+    fn gif(frame_count: usize, size: u16, delay: u16) -> Vec<u8> {
+        let mut blob = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut blob, size, size, &[]).unwrap();
+            for i in 0..frame_count {
+                let mut rgba = [(i * 20) as u8, 0, 0, 0xff].repeat(size as usize * size as usize);
+                let mut frame = gif::Frame::from_rgba(size, size, &mut rgba);
+                frame.delay = delay;
+                encoder.write_frame(&frame).unwrap();
             }
-            Some(img) => img
-        })
+        }
+        blob
+    }
+
+    #[test]
+    fn gif_within_pixel_limit() {
+        let (images, durations) = decode_avatar_with_limit(&gif(10, 4, 5), "test", 4 * 4 * 10).unwrap();
+        assert_eq!(images.len(), 10);
+        assert_eq!(durations, vec![50; 10]);
+    }
+
+    #[test]
+    fn gif_sampled_to_pixel_limit() {
+        let (images, durations) = decode_avatar_with_limit(&gif(10, 4, 5), "test", 4 * 4 * 4).unwrap();
+        assert_eq!(images.len(), 4);
+        assert_eq!(durations, vec![100, 150, 100, 150]);
+        assert_eq!(durations.iter().sum::<u32>(), 500);
+    }
+
+    #[test]
+    fn frame_over_pixel_limit() {
+        let result = decode_avatar_with_limit(&gif(10, 4, 5), "test", 4 * 4 - 1);
+        assert!(matches!(result, Err(AvatarTooLargeError(_))));
+    }
+
+    #[test]
+    fn sampled_frame_indices() {
+        let kept = |frame_count, max_frames| sampled_frames(frame_count, max_frames).iter()
+            .enumerate()
+            .filter(|(_, keep)| **keep)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(kept(10, 4), vec![0, 2, 5, 7]);
+        assert_eq!(kept(3, 5), vec![0, 1, 2]);
+        assert_eq!(kept(5, 1), vec![0]);
     }
 }
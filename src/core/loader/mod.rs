@@ -1,2 +1,5 @@
 pub mod image_loader;
-pub mod color_util;
\ No newline at end of file
+pub mod animation;
+pub mod frame_limit;
+pub mod color_util;
+pub mod placeholder;
\ No newline at end of file
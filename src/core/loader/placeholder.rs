@@ -0,0 +1,22 @@
+use skia_safe::{Color, Image, Paint};
+
+pub static PLACEHOLDER_SIZE: i32 = 256;
+
+/// solid background with a lighter circle
+pub fn placeholder_image(color: Color) -> Image {
+    let mut surface = skia_safe::surfaces::raster_n32_premul((PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)).unwrap();
+    let canvas = surface.canvas();
+    canvas.clear(color);
+    let mut paint = Paint::default();
+    paint.set_anti_alias(true);
+    paint.set_color(Color::from_argb(0x80, 0xff, 0xff, 0xff));
+    let half = PLACEHOLDER_SIZE as f32 / 2.0;
+    canvas.draw_circle((half, half), half * 0.6, &paint);
+    surface.image_snapshot()
+}
+
+pub fn solid_image(color: Color) -> Image {
+    let mut surface = skia_safe::surfaces::raster_n32_premul((PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)).unwrap();
+    surface.canvas().clear(color);
+    surface.image_snapshot()
+}
@@ -9,18 +9,19 @@ use skia_safe::canvas::SrcRectConstraint;
 
 use crate::core::builder::avatar_builder::{AvatarBuiltTemplate, AvatarFrames};
 use crate::core::builder::background_builder::OriginSize;
-use crate::core::builder::pos_builder::{CompiledNumberPosDimension, eval_size, XYWH};
+use crate::core::builder::pos_builder::{CompiledFloat, CompiledNumberPosDimension, eval_pos, ExprContext, helper_context, set_size_vars, XYWH};
 use crate::core::errors::Error;
 use crate::core::errors::Error::{AvatarLoadError, TemplateError};
 use crate::core::filters::filters::build_filter;
-use crate::core::template::avatar_template::{AvatarCropType, AvatarFit, AvatarStyle, CropPos};
+use crate::core::template::avatar_template::{AvatarCropType, AvatarFit, AvatarStyle};
 use crate::core::template::petpet_template::TransformOrigin;
 
 pub struct AvatarModel<'a> {
     pub template: &'a AvatarBuiltTemplate,
     images: Arc<Vec<Image>>,
     pub pos: Cow<'a, CompiledNumberPosDimension>,
-    pub delay: u16,
+    /// evaluated `template.angle`
+    angles: Vec<f32>,
 
     src_rect: Option<Rect>,
 }
@@ -31,52 +32,100 @@ impl<'a> AvatarModel<'a> {
     pub fn new(
         template: &'a AvatarBuiltTemplate,
         frames: AvatarFrames,
+        frame_ms: u32,
     ) -> Result<AvatarModel<'a>, Error> {
         if frames.0.as_ref().is_empty() {
             return Err(AvatarLoadError("avatars vec is empty".to_string()));
         }
-        let (num_pos, expr_pos) = &template.pos;
+        let images = Self::retime_images(frames, frame_ms);
+        let built_images: Arc<Vec<Image>> = Self::pre_build_images(template, images);
+        let src_rect = Self::eval_crop(template, Self::get_image_size(&built_images[0]))?;
+        let angles = template.angle.iter().map(|angle| match angle {
+            CompiledFloat::Num(angle) => *angle,
+            CompiledFloat::Expr(_) => 0.0,
+        }).collect();
 
-        let built_images: Arc<Vec<Image>> = Self::pre_build_images(template, frames.0);
+        Ok(AvatarModel {
+            template,
+            images: built_images,
+            pos: Cow::Borrowed(&template.pos.0),
+            angles,
+            src_rect,
+        })
+    }
 
-        let src_rect = match template.raw.crop_type {
+    /// crop expressions only see the size of this avatar
+    fn eval_crop(template: &AvatarBuiltTemplate, size: OriginSize) -> Result<Option<Rect>, Error> {
+        let crop = match &template.crop {
+            Some(crop) => crop,
+            None => return Ok(None),
+        };
+        let mut ctx = helper_context();
+        set_size_vars(&mut ctx, size);
+        let xyxy = crop.iter()
+            .map(|item| item.eval(&ctx))
+            .collect::<Result<Vec<f32>, Error>>()?;
+        let (x1, y1, x2, y2) = (xyxy[0], xyxy[1], xyxy[2], xyxy[3]);
+        Ok(match template.raw.crop_type {
             AvatarCropType::NONE => None,
-            AvatarCropType::PIXEL => {
-                if let CropPos::XYXY((x1, y1, x2, y2)) = template.raw.crop.as_ref().unwrap() {
-                    Some(Rect::from_xywh(*x1, *y1, x2 - x1, y2 - y1))
-                } else { None }
-            }
+            AvatarCropType::PIXEL => Some(Rect::from_xywh(x1, y1, x2 - x1, y2 - y1)),
             AvatarCropType::PERCENT => {
-                if let CropPos::XYXY((x1, y1, x2, y2)) = template.raw.crop.as_ref().unwrap() {
-                    let (sw, sh) = Self::get_image_size(&built_images[0]);
-                    let x1 = (x1 / 100.0) * sw as f32;
-                    let y1 = (y1 / 100.0) * sh as f32;
-                    let x2 = (x2 / 100.0) * sw as f32;
-                    let y2 = (y2 / 100.0) * sh as f32;
-                    Some(Rect::from_xywh(x1, y1, x2 - x1, y2 - y1))
-                } else { None }
+                let (sw, sh) = size;
+                let x1 = (x1 / 100.0) * sw as f32;
+                let y1 = (y1 / 100.0) * sh as f32;
+                let x2 = (x2 / 100.0) * sw as f32;
+                let y2 = (y2 / 100.0) * sh as f32;
+                Some(Rect::from_xywh(x1, y1, x2 - x1, y2 - y1))
             }
-        };
+        })
+    }
+
+    /// evaluate position and angle expressions once canvas size and frame count are known
+    pub fn eval_expr(&mut self, ctx: &ExprContext) -> Result<(), Error> {
+        let template = self.template;
+        let (num_pos, expr_pos) = &template.pos;
+        let angle_expr = template.angle.iter().any(|angle| angle.is_expr());
+        if expr_pos.is_empty() && !angle_expr {
+            return Ok(());
+        }
+        let length = ctx.frames.unwrap_or(1);
+        let mut ctx = ctx.to_meval();
+        set_size_vars(&mut ctx, Self::get_image_size(&self.images[0]));
 
         if !expr_pos.is_empty() {
-            let size = Self::get_image_size(&built_images[0]);
-            let pos = eval_size((num_pos, expr_pos), size)?;
-            return Ok(AvatarModel {
-                template,
-                images: built_images,
-                pos: Cow::Owned(pos),
-                delay: frames.1,
-                src_rect,
-            });
+            self.pos = Cow::Owned(eval_pos((num_pos, expr_pos), &mut ctx, length)?);
         }
+        if angle_expr {
+            let length = usize::max(length, template.angle.len());
+            let mut angles = Vec::with_capacity(length);
+            for i in 0..length {
+                ctx.var("frame", i as f64);
+                angles.push(template.angle[i % template.angle.len()].eval(&ctx)? % 360.0);
+            }
+            self.angles = angles;
+        }
+        Ok(())
+    }
 
-        Ok(AvatarModel {
-            template,
-            images: built_images,
-            pos: Cow::Borrowed(num_pos),
-            delay: frames.1,
-            src_rect,
-        })
+    /// resample frames by time so that each output frame lasts `frame_ms`
+    fn retime_images((images, durations): AvatarFrames, frame_ms: u32) -> Arc<Vec<Image>> {
+        let total: u32 = durations.iter().sum();
+        if images.len() <= 1 || total == 0 || frame_ms == 0 {
+            return images;
+        }
+        let ends: Vec<u32> = durations.iter()
+            .scan(0, |end, duration| {
+                *end += duration;
+                Some(*end)
+            })
+            .collect();
+        let length = usize::max(1, (total as f32 / frame_ms as f32).round() as usize);
+        let retimed = (0..length).map(|i| {
+            let time = (i as u32 * frame_ms) % total;
+            let source = ends.partition_point(|end| *end <= time);
+            images[usize::min(source, images.len() - 1)].clone()
+        }).collect();
+        Arc::new(retimed)
     }
 
     fn pre_build_images(
@@ -118,8 +167,9 @@ impl<'a> AvatarModel<'a> {
         }
     }
 
+    /// avatar frames or per-frame template values, whichever is longer
     pub fn get_length(&self) -> usize {
-        self.images.len()
+        usize::max(self.images.len(), self.template.frame_length)
     }
 
     fn get_image_size(image: &Image) -> OriginSize {
@@ -147,9 +197,19 @@ impl<'a> AvatarModel<'a> {
                 ], &p3d[index % p3d.len()]).ok_or_else(|| TemplateError(
                     format!("can not build Matrix, {:?}", &p3d[index % p3d.len()])
                 ))?;
+                let points = &p3d[index % p3d.len()];
+                let pivot = match self.template.raw.origin {
+                    TransformOrigin::DEFAULT => points[0],
+                    TransformOrigin::CENTER => Point::new(
+                        points.iter().map(|p| p.x).sum::<f32>() / 4.0,
+                        points.iter().map(|p| p.y).sum::<f32>() / 4.0,
+                    ),
+                };
+                canvas.save();
+                self.transform(canvas, pivot, self.get_angle(index), self.get_scale(index));
                 canvas.concat(&m);
-                canvas.draw_image(img, (0, 0), None);
-                canvas.reset_matrix();
+                canvas.draw_image(img, (0, 0), Some(&self.get_paint(index)));
+                canvas.restore();
             }
         };
         Ok(())
@@ -162,22 +222,23 @@ impl<'a> AvatarModel<'a> {
         (x, y, w, h): XYWH,
         index: usize,
     ) {
-        let mut paint = Paint::default();
-        paint.set_alpha((self.template.raw.opacity * 255.0) as u8);
+        let paint = self.get_paint(index);
 
         let scaled = self.scale_img(canvas, (x, y, w, h));
 
+        let base_angle = self.get_angle(index);
         let angle = if self.template.raw.rotate {
-            (360.0 / self.template.max_length as f32) * index as f32 + self.template.raw.angle
-        } else { self.template.raw.angle };
-        let has_angle = angle != 0.0;
-        if has_angle {
+            (360.0 / self.template.max_length as f32) * index as f32 + base_angle
+        } else { base_angle };
+        let scale = self.get_scale(index);
+        let transformed = angle != 0.0 || scale != 1.0;
+        if transformed {
             canvas.save();
             let p = match self.template.raw.origin {
                 TransformOrigin::DEFAULT => Point::from((x, y)),
                 TransformOrigin::CENTER => Point::from((x + w / 2, y + h / 2)),
             };
-            canvas.rotate(angle, Some(p));
+            self.transform(canvas, p, angle, scale);
         }
 
         let wf = w as f32;
@@ -230,7 +291,7 @@ impl<'a> AvatarModel<'a> {
                 );
             }
         }
-        if has_angle {
+        if transformed {
             canvas.restore();
         }
         if scaled {
@@ -238,6 +299,34 @@ impl<'a> AvatarModel<'a> {
         }
     }
 
+    fn get_angle(&self, index: usize) -> f32 {
+        self.angles[index % self.angles.len()]
+    }
+
+    fn get_scale(&self, index: usize) -> f32 {
+        let scale = &self.template.scale;
+        scale[index % scale.len()]
+    }
+
+    /// rotate then scale around `pivot`, the caller saves and restores the canvas
+    fn transform(&self, canvas: &Canvas, pivot: Point, angle: f32, scale: f32) {
+        if angle != 0.0 {
+            canvas.rotate(angle, Some(pivot));
+        }
+        if scale != 1.0 {
+            canvas.translate(pivot);
+            canvas.scale((scale, scale));
+            canvas.translate(pivot.neg());
+        }
+    }
+
+    fn get_paint(&self, index: usize) -> Paint {
+        let opacity = &self.template.opacity;
+        let mut paint = Paint::default();
+        paint.set_alpha((opacity[index % opacity.len()] * 255.0) as u8);
+        paint
+    }
+
     fn scale_img(&self, canvas: &Canvas, (x, y, w, h): XYWH) -> bool {
         if self.template.raw.style.is_empty() {
             return false
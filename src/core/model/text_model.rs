@@ -13,6 +13,20 @@ static TEXT_VAR_REGEX: Lazy<Regex> = Lazy::new(||
     Regex::new(r#"\$txt([1-9]\d*)\[(.*?)]"#).unwrap()
 );
 
+/// variables used by text, `$txtN` is reported without the default content
+pub fn text_variables(text: &str) -> Vec<String> {
+    let mut vars: Vec<String> = ["$from", "$to", "$group"].iter()
+        .filter(|var| text.contains(*var))
+        .map(|var| var.to_string())
+        .collect();
+    for cap in TEXT_VAR_REGEX.captures_iter(text) {
+        if let Some(num) = cap.get(1) {
+            vars.push(format!("$txt{}", num.as_str()));
+        }
+    }
+    vars
+}
+
 pub struct TextModel<'a> {
     pub template: &'a TextBuiltTemplate,
     // Paragraph is neither Send nor Sync
@@ -47,30 +61,34 @@ impl<'a> TextModel<'a> {
         }
     }
 
-    pub fn draw(&self, canvas: &Canvas) {
-        let (x, y, width) = match self.template.raw.pos {
+    pub fn draw(&self, canvas: &Canvas, index: usize) {
+        if !self.is_visible(index) {
+            return;
+        }
+        let (x, y, width) = match self.get_pos(index) {
             TextPos::XY((x, y)) =>
-                (x, y, match self.template.raw.align {
+                (*x, *y, match self.template.raw.align {
                     TextAlign::LEFT => canvas.image_info().width() - x,
                     TextAlign::CENTER => canvas.image_info().width() / 2,
-                    TextAlign::RIGHT => x,
+                    TextAlign::RIGHT => *x,
                 }),
-            TextPos::XYW((x, y, w)) => (x, y, w),
+            TextPos::XYW((x, y, w)) => (*x, *y, *w),
         };
-        let (mut fill_p, mut stroke_p) = self.build_paragraph(width as f32);
+        let size = *frame_value(&self.template.raw.size, index);
+        let (mut fill_p, mut stroke_p) = self.build_paragraph(width as f32, index);
 
         if let TextWrap::ZOOM = self.template.raw.wrap {
             if let Some(mut p) = fill_p {
                 p.layout(f32::MAX);
-                let font_size = f32::floor(self.template.raw.size * (width as f32 / p.max_intrinsic_width()));
+                let font_size = f32::floor(size * (width as f32 / p.max_intrinsic_width()));
                 fill_p = Some(single_paragraph(
                     &self.template, &self.text, font_size,
-                    &self.template.fill_paint.as_ref().unwrap(), width as f32,
+                    frame_value(&self.template.fill_paint, index).as_ref().unwrap(), width as f32,
                 ))
             }
             if let Some(mut p) = stroke_p {
                 p.layout(f32::MAX);
-                let font_size = f32::floor(self.template.raw.size * (width as f32 / p.max_intrinsic_width()));
+                let font_size = f32::floor(size * (width as f32 / p.max_intrinsic_width()));
                 stroke_p = Some(single_paragraph(
                     &self.template, &self.text, font_size,
                     &self.template.stroke_paint.as_ref().unwrap(), width as f32,
@@ -78,21 +96,19 @@ impl<'a> TextModel<'a> {
             }
         }
 
-        let has_angle = self.template.raw.angle != 0.0;
+        let height = match (&fill_p, &stroke_p) {
+            (Some(p), _) | (None, Some(p)) => p.height(),
+            (None, None) => return,
+        };
+        let angle = *frame_value(&self.template.raw.angle, index);
+        let has_angle = angle != 0.0;
         if has_angle {
             canvas.save();
             let p = match self.template.raw.origin {
                 TransformOrigin::DEFAULT => Point::from((x, y)),
-                TransformOrigin::CENTER => Point::from((
-                    x + width / 2,
-                    y + if let Some(fp) = &fill_p {
-                        fp.height()
-                    } else {
-                        stroke_p.as_ref().unwrap().height()
-                    } as i32 / 2
-                )),
+                TransformOrigin::CENTER => Point::from((x + width / 2, y + height as i32 / 2)),
             };
-            canvas.rotate(self.template.raw.angle, Some(p));
+            canvas.rotate(angle, Some(p));
         }
 
         if let Some(p) = fill_p {
@@ -108,36 +124,65 @@ impl<'a> TextModel<'a> {
         ()
     }
 
+    /// size of the first frame
     pub fn get_size(&self) -> (i32, i32) {
-        let width = match self.template.raw.pos {
+        let width = match self.get_pos(0) {
             TextPos::XY(_) => 200,
-            TextPos::XYW((_, _, w)) => w,
+            TextPos::XYW((_, _, w)) => *w,
         };
-        let (fill_p, stroke_p) = self.build_paragraph(width as f32);
-        let height = if fill_p.is_some() {
-            fill_p.unwrap().height()
-        } else {
-            stroke_p.unwrap().height()
-        } as i32;
+        let (fill_p, stroke_p) = self.build_paragraph(width as f32, 0);
+        let height = fill_p.or(stroke_p).map_or(0.0, |p| p.height()) as i32;
         (width, height)
     }
 
-    fn build_paragraph(&self, max_width: f32) -> (Option<Paragraph>, Option<Paragraph>) {
+    pub fn get_line_count(&self) -> usize {
+        let width = match self.get_pos(0) {
+            TextPos::XY(_) => 200,
+            TextPos::XYW((_, _, w)) => *w,
+        };
+        let (fill_p, stroke_p) = self.build_paragraph(width as f32, 0);
+        fill_p.or(stroke_p).map_or(0, |p| p.line_number())
+    }
+
+    /// longest per-frame list or the end of the visible range
+    pub fn get_length(&self) -> usize {
+        self.template.frame_length
+    }
+
+    fn is_visible(&self, index: usize) -> bool {
+        match self.template.raw.visible.as_slice() {
+            [start] => index >= *start,
+            [start, end] => index >= *start && index <= *end,
+            _ => true,
+        }
+    }
+
+    fn get_pos(&self, index: usize) -> &TextPos {
+        frame_value(&self.template.raw.pos, index)
+    }
+
+    fn build_paragraph(&self, max_width: f32, index: usize) -> (Option<Paragraph>, Option<Paragraph>) {
+        let size = *frame_value(&self.template.raw.size, index);
         let mut result = (None, None);
-        if let Some(paint) = &self.template.fill_paint {
+        if let Some(paint) = frame_value(&self.template.fill_paint, index) {
             result.0 = Some(single_paragraph(
-                &self.template, &self.text, self.template.raw.size, paint, max_width,
+                &self.template, &self.text, size, paint, max_width,
             ))
         }
         if let Some(paint) = &self.template.stroke_paint {
             result.1 = Some(single_paragraph(
-                &self.template, &self.text, self.template.raw.size, paint, max_width,
+                &self.template, &self.text, size, paint, max_width,
             ))
         }
         result
     }
 }
 
+/// per-frame lists are cycled by frame index
+fn frame_value<T>(values: &[T], index: usize) -> &T {
+    &values[index % values.len()]
+}
+
 fn single_paragraph(
     template: &TextBuiltTemplate,
     text: &str,
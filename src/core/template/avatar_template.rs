@@ -1,6 +1,6 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::core::template::filter_template::AvatarFilter;
+use crate::core::template::filter_template::{AvatarFilter, f32_or_vec};
 use crate::core::template::petpet_template::TransformOrigin;
 
 #[repr(u8)]
@@ -14,6 +14,96 @@ pub enum AvatarType {
     RANDOM,
 }
 
+/// used when an avatar is missing or fails to load, tried in order.
+/// `"PLACEHOLDER"`, `{"COLOR": "#ffffff"}` or `{"AVATAR": "FROM"}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum AvatarFallback {
+    PLACEHOLDER,
+    COLOR(String),
+    AVATAR(AvatarType),
+}
+
+/// how an animated avatar is cut down to its frame limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum FrameLimitStrategy {
+    /// keep evenly spaced frames over the whole animation, dropped frames extend the kept ones
+    SAMPLE,
+    /// keep the leading frames
+    TRUNCATE,
+}
+
+/// limits of animated avatar input, unset fields use the global limit
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AvatarFrameLimit {
+    #[serde(default = "limit_default", rename = "maxFrames")]
+    pub max_frames: Option<usize>,
+    /// ms, always truncates
+    #[serde(default = "limit_default", rename = "maxDuration")]
+    pub max_duration: Option<u32>,
+    /// width * height * frame count
+    #[serde(default = "limit_default", rename = "maxPixels")]
+    pub max_pixels: Option<u64>,
+    #[serde(default = "strategy_default")]
+    pub strategy: Option<FrameLimitStrategy>,
+}
+
+impl AvatarFrameLimit {
+    /// fields of `self` take precedence over `other`
+    pub fn or(&self, other: &AvatarFrameLimit) -> AvatarFrameLimit {
+        AvatarFrameLimit {
+            max_frames: self.max_frames.or(other.max_frames),
+            max_duration: self.max_duration.or(other.max_duration),
+            max_pixels: self.max_pixels.or(other.max_pixels),
+            strategy: self.strategy.clone().or_else(|| other.strategy.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum KeyframeEasing {
+    LINEAR,
+    #[allow(non_camel_case_types)]
+    EASE_IN,
+    #[allow(non_camel_case_types)]
+    EASE_OUT,
+    #[allow(non_camel_case_types)]
+    EASE_IN_OUT,
+    /// hold the value until the next keyframe
+    STEP,
+}
+
+/// sparse keyframes expanded to per-frame values when the template is built.
+/// values are held before the first and after the last keyframe that sets them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AvatarKeyframes {
+    /// frame count, defaults to the last keyframe + 1 or the background length if longer
+    #[serde(default = "keyframes_length_default")]
+    pub length: Option<usize>,
+    #[serde(default = "easing_default")]
+    pub easing: KeyframeEasing,
+    pub frames: Vec<AvatarKeyframe>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AvatarKeyframe {
+    pub frame: usize,
+    /// `[x, y, w, h]` for ZOOM, five `[x, y]` points for DEFORM, numbers only
+    #[serde(default = "keyframe_pos_default")]
+    pub pos: Option<PosDimension>,
+    #[serde(default = "keyframe_value_default")]
+    pub angle: Option<f32>,
+    #[serde(default = "keyframe_value_default")]
+    pub opacity: Option<f32>,
+    #[serde(default = "keyframe_value_default")]
+    pub scale: Option<f32>,
+    /// easing towards the next keyframe, overrides `AvatarKeyframes.easing`
+    #[serde(default = "keyframe_easing_default")]
+    pub easing: Option<KeyframeEasing>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
 pub enum AvatarPosType {
@@ -50,6 +140,8 @@ pub enum AvatarStyle {
 pub struct AvatarTemplate {
     #[serde(rename = "type")]
     pub _type: AvatarType,
+    /// may be omitted if `keyframes` set it
+    #[serde(default = "pos_default")]
     pub pos: PosDimension,
     #[serde(rename = "posType", default = "pos_type_default")]
     pub pos_type: AvatarPosType,
@@ -75,10 +167,18 @@ pub struct AvatarTemplate {
     pub antialias: bool,
     #[serde(default = "resampling_default")]
     pub resampling: bool,
-    #[serde(default = "angle_default")]
-    pub angle: f32,
-    #[serde(default = "opacity_default")]
-    pub opacity: f32,
+    /// number, expression or per-frame list of them, cycled by frame index
+    #[serde(default = "angle_default", deserialize_with = "float_item_or_vec")]
+    pub angle: Vec<FloatItem>,
+    /// number or per-frame list
+    #[serde(default = "opacity_default", deserialize_with = "f32_or_vec")]
+    pub opacity: Vec<f32>,
+    /// number or per-frame list, around `origin` like `angle`
+    #[serde(default = "scale_default", deserialize_with = "f32_or_vec")]
+    pub scale: Vec<f32>,
+    /// replaces `pos`, `angle`, `opacity` and `scale` with interpolated per-frame values
+    #[serde(default = "keyframes_default")]
+    pub keyframes: Option<AvatarKeyframes>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
@@ -104,11 +204,23 @@ pub enum PosDimension {
     P3D(P3D),
 }
 
+/// number or expression, see `ExprContext` for variables
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FloatItem {
+    Expr(String),
+    Num(f32),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum CropPos {
-    WH((f32, f32)),
-    XYXY((f32, f32, f32, f32)),
+    WH((FloatItem, FloatItem)),
+    XYXY((FloatItem, FloatItem, FloatItem, FloatItem)),
+}
+
+fn pos_default() -> PosDimension {
+    PosDimension::P2D(Vec::new())
 }
 
 fn pos_type_default() -> AvatarPosType {
@@ -159,10 +271,62 @@ fn resampling_default() -> bool {
     true
 }
 
-fn angle_default() -> f32 {
-    0.0
+fn angle_default() -> Vec<FloatItem> {
+    vec![FloatItem::Num(0.0)]
 }
 
-fn opacity_default() -> f32 {
-    1.0
-}
\ No newline at end of file
+fn opacity_default() -> Vec<f32> {
+    vec![1.0]
+}
+
+fn scale_default() -> Vec<f32> {
+    vec![1.0]
+}
+
+fn float_item_or_vec<'de, D>(deserializer: D) -> Result<Vec<FloatItem>, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum FloatItemOrVec {
+        Item(FloatItem),
+        Vec(Vec<FloatItem>),
+    }
+
+    Ok(match FloatItemOrVec::deserialize(deserializer)? {
+        FloatItemOrVec::Item(item) => vec![item],
+        FloatItemOrVec::Vec(items) => items,
+    })
+}
+
+fn keyframes_default() -> Option<AvatarKeyframes> {
+    None
+}
+
+fn keyframes_length_default() -> Option<usize> {
+    None
+}
+
+fn easing_default() -> KeyframeEasing {
+    KeyframeEasing::LINEAR
+}
+
+fn keyframe_pos_default() -> Option<PosDimension> {
+    None
+}
+
+fn keyframe_value_default() -> Option<f32> {
+    None
+}
+
+fn keyframe_easing_default() -> Option<KeyframeEasing> {
+    None
+}
+
+fn limit_default<T>() -> Option<T> {
+    None
+}
+
+fn strategy_default() -> Option<FrameLimitStrategy> {
+    None
+}
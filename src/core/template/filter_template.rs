@@ -83,7 +83,7 @@ impl UniformsBuilder<'_> {
     }
 }
 
-fn f32_or_vec<'de, D>(deserializer: D) -> Result<Vec<f32>, D::Error>
+pub(crate) fn f32_or_vec<'de, D>(deserializer: D) -> Result<Vec<f32>, D::Error>
     where D: Deserializer<'de>
 {
     struct F32OrVec;
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::core::template::avatar_template::AvatarTemplate;
+use crate::core::template::avatar_template::{AvatarFallback, AvatarFrameLimit, AvatarTemplate};
 use crate::core::template::background_template::BackgroundTemplate;
 use crate::core::template::text_template::TextTemplate;
 
@@ -30,6 +30,10 @@ pub struct PetpetTemplate {
     pub reverse: bool,
     #[serde(default = "hidden_default")]
     pub hidden: bool,
+    #[serde(default = "fallback_default")]
+    pub fallback: Vec<AvatarFallback>,
+    #[serde(default = "frame_limit_default", rename = "frameLimit")]
+    pub frame_limit: AvatarFrameLimit,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -70,3 +74,11 @@ fn reverse_default() -> bool {
 fn hidden_default() -> bool {
     false
 }
+
+fn fallback_default() -> Vec<AvatarFallback> {
+    Vec::new()
+}
+
+fn frame_limit_default() -> AvatarFrameLimit {
+    AvatarFrameLimit::default()
+}
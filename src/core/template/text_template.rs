@@ -5,6 +5,7 @@ use serde::de::{SeqAccess, value, Visitor};
 use skia_safe::Point;
 use skia_safe::textlayout::Paragraph;
 
+use crate::core::template::filter_template::f32_or_vec;
 use crate::core::template::petpet_template::TransformOrigin;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -75,17 +76,19 @@ impl TextStyle {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextTemplate {
     pub text: String,
-    pub pos: TextPos,
-    #[serde(default = "size_default")]
-    pub size: f32,
-    #[serde(default = "angle_default")]
-    pub angle: f32,
+    /// `pos`, `size`, `angle` and `color` also take a per-frame list, cycled by frame index
+    #[serde(deserialize_with = "text_pos_or_vec")]
+    pub pos: Vec<TextPos>,
+    #[serde(default = "size_default", deserialize_with = "f32_or_vec")]
+    pub size: Vec<f32>,
+    #[serde(default = "angle_default", deserialize_with = "f32_or_vec")]
+    pub angle: Vec<f32>,
     #[serde(default = "align_default")]
     pub align: TextAlign,
     #[serde(default = "wrap_default")]
     pub wrap: TextWrap,
-    #[serde(default = "color_default")]
-    pub color: String,
+    #[serde(default = "color_default", deserialize_with = "string_or_vec")]
+    pub color: Vec<String>,
     #[serde(default = "font_default", deserialize_with = "string_or_vec")]
     pub font: Vec<String>,
     #[serde(default = "style_default")]
@@ -96,6 +99,10 @@ pub struct TextTemplate {
     pub stroke_size: f32,
     #[serde(default = "origin_default")]
     pub origin: TransformOrigin,
+    /// frames the text is drawn on, `[start, end]` inclusive or `[start]` until the last frame,
+    /// empty for all frames
+    #[serde(default = "visible_default", alias = "frames")]
+    pub visible: Vec<usize>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -149,12 +156,28 @@ fn string_or_vec<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
     deserializer.deserialize_any(StringOrVec)
 }
 
-fn size_default() -> f32 {
-    24.0
+fn text_pos_or_vec<'de, D>(deserializer: D) -> Result<Vec<TextPos>, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TextPosOrVec {
+        Pos(TextPos),
+        Vec(Vec<TextPos>),
+    }
+
+    Ok(match TextPosOrVec::deserialize(deserializer)? {
+        TextPosOrVec::Pos(pos) => vec![pos],
+        TextPosOrVec::Vec(pos) => pos,
+    })
 }
 
-fn angle_default() -> f32 {
-    0.0
+fn size_default() -> Vec<f32> {
+    vec![24.0]
+}
+
+fn angle_default() -> Vec<f32> {
+    vec![0.0]
 }
 
 fn align_default() -> TextAlign {
@@ -165,8 +188,8 @@ fn wrap_default() -> TextWrap {
     TextWrap::BREAK
 }
 
-fn color_default() -> String {
-    "#ffffff".to_string()
+fn color_default() -> Vec<String> {
+    vec!["#ffffff".to_string()]
 }
 
 fn font_default() -> Vec<String> {
@@ -189,6 +212,10 @@ fn origin_default() -> TransformOrigin {
     TransformOrigin::DEFAULT
 }
 
+fn visible_default() -> Vec<usize> {
+    Vec::new()
+}
+
 fn from_default() -> String {
     "from".to_string()
 }
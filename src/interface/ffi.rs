@@ -1,17 +1,39 @@
 use std::collections::HashMap;
 use std::ffi::{c_char, CStr, CString};
+use std::fmt::Display;
+use std::str::FromStr;
 use crate::core::builder::petpet_builder::PetpetBuilder;
-use crate::core::encoder::encoder::IMAGE_ENCODER;
+use crate::core::encoder::encoder::{EncodeFormat, IMAGE_ENCODER};
+use crate::core::errors::Error;
 use crate::core::http::avatar_data_factory::create_avatar_data;
+use crate::core::http::requester;
+use crate::core::http::requester::RequesterOptions;
 use crate::core::http::template_data::AvatarDataURL;
 use crate::core::template::petpet_template::PetpetTemplate;
 use crate::core::template::text_template::TextData;
 
+/// `error` is null on success, otherwise `data` and `format` are null
 #[repr(C)]
 pub struct FFIResult {
     data: *const u8,
     length: usize,
     format: *const c_char,
+    error: *const c_char,
+}
+
+impl FFIResult {
+    fn error(err: impl Display) -> FFIResult {
+        FFIResult {
+            data: std::ptr::null(),
+            length: 0,
+            format: std::ptr::null(),
+            error: error_string(err),
+        }
+    }
+}
+
+fn error_string(err: impl Display) -> *const c_char {
+    CString::new(err.to_string()).unwrap_or_default().into_raw()
 }
 
 #[repr(C)]
@@ -54,13 +76,77 @@ pub extern "C" fn create_builder(
     }
 }
 
+/// `options` is a JSON string, same fields as `requester` in server config.
+/// returns null on success, otherwise the error message
+#[no_mangle]
+pub extern "C" fn set_requester_options(options: *const c_char) -> *const c_char {
+    let options_cstr = unsafe { CStr::from_ptr(options) };
+    let result = serde_json::from_str::<RequesterOptions>(&options_cstr.to_string_lossy())
+        .map_err(Error::from)
+        .and_then(requester::set_requester_options);
+    match result {
+        Ok(_) => std::ptr::null(),
+        Err(e) => error_string(e),
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn builder_build(
     builder: *const PetpetBuilder,
     avatar_params: *const BuildParams,
     text_params: *const BuildParams
 ) -> FFIResult {
-    let runtime = tokio::runtime::Runtime::new().unwrap();
+    build_with_format(builder, avatar_params, text_params, None)
+}
+
+/// `format`: "png" | "gif" | "webp" | "apng", null to decide by frame count
+#[no_mangle]
+pub extern "C" fn builder_build_with_format(
+    builder: *const PetpetBuilder,
+    avatar_params: *const BuildParams,
+    text_params: *const BuildParams,
+    format: *const c_char,
+) -> FFIResult {
+    let format = if format.is_null() {
+        None
+    } else {
+        let format_cstr = unsafe { CStr::from_ptr(format) };
+        match EncodeFormat::from_str(&format_cstr.to_string_lossy()) {
+            Ok(format) => Some(format),
+            Err(e) => return FFIResult::error(e),
+        }
+    };
+    build_with_format(builder, avatar_params, text_params, format)
+}
+
+fn build_with_format(
+    builder: *const PetpetBuilder,
+    avatar_params: *const BuildParams,
+    text_params: *const BuildParams,
+    format: Option<EncodeFormat>,
+) -> FFIResult {
+    let (blob, format) = match build_blob(builder, avatar_params, text_params, format) {
+        Ok(result) => result,
+        Err(e) => return FFIResult::error(e),
+    };
+    let ptr = blob.as_ptr();
+    let length = blob.len();
+    std::mem::forget(blob);
+    FFIResult {
+        data: ptr,
+        length,
+        format: CString::new(format.to_format()).unwrap_or_default().into_raw(),
+        error: std::ptr::null(),
+    }
+}
+
+fn build_blob(
+    builder: *const PetpetBuilder,
+    avatar_params: *const BuildParams,
+    text_params: *const BuildParams,
+    format: Option<EncodeFormat>,
+) -> Result<(Vec<u8>, EncodeFormat), Error> {
+    let runtime = tokio::runtime::Runtime::new()?;
     //TODO
     let (avatar_map, avatar_list) = params_to_map_list(avatar_params);
     let (text_map, text_list) = params_to_map_list(text_params);
@@ -80,25 +166,20 @@ pub extern "C" fn builder_build(
                 bot: avatar_map.get(bot_key).cloned(),
                 group: avatar_map.get(group_key).cloned(),
                 random: Some(avatar_list),
+                base64: None,
+                fallback: None,
             }
-        ).unwrap(),
+        )?,
         TextData {
             from: text_map.get(form_key).unwrap_or(&form_key.to_owned()).to_owned(),
             to: text_map.get(to_key).unwrap_or(&to_key.to_owned()).to_owned(),
             group: text_map.get(group_key).unwrap_or(&group_key.to_owned()).to_owned(),
             text_list,
         },
-    )).unwrap();
-    let (blob, format) = IMAGE_ENCODER.encode(&images, delay).unwrap();
-    let ptr = blob.as_ptr();
-    let length = blob.len();
-    let format = CString::new(format.to_format()).unwrap();
-    std::mem::forget(blob);
-    std::mem::forget(format);
-    FFIResult {
-        data: ptr,
-        length,
-        format: format.as_ptr() as *const c_char,
+    ))?;
+    match format {
+        Some(format) => IMAGE_ENCODER.encode_as(&images, delay, format),
+        None => IMAGE_ENCODER.encode(&images, delay),
     }
 }
 
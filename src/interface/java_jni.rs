@@ -1,15 +1,19 @@
 extern crate jni;
 
 use crate::core::builder::petpet_builder::PetpetBuilder;
-use crate::core::encoder::encoder::{EncodeFormat, IMAGE_ENCODER};
+use std::str::FromStr;
+
+use crate::core::encoder::encoder::{EncodeFormat, EncodeOptions, IMAGE_ENCODER};
 use crate::core::errors::Error;
+use crate::core::errors::Error::ImageEncodeError;
 use crate::core::http::avatar_data_factory::create_avatar_data;
+use crate::core::http::requester::{RequesterOptions, set_requester_options};
 use crate::core::http::template_data::{AvatarDataURL, PetpetData};
 use crate::core::template::petpet_template::PetpetTemplate;
 use crate::core::template::text_template::TextData;
 
 use jni::JNIEnv;
-use jni::objects::{JByteArray, JClass, JObject, JString, AsJArrayRaw, JObjectArray};
+use jni::objects::{JClass, JObject, JString, JValue, AsJArrayRaw, JObjectArray};
 use once_cell::sync::Lazy;
 
 
@@ -17,6 +21,33 @@ static RUNTIME: Lazy<tokio::runtime::Runtime> = Lazy::new(||
 tokio::runtime::Runtime::new().unwrap()
 );
 
+static RESULT_CLASS: &str = "moe/d2n/petpetrs/PetpetRsBuilder$Result";
+
+/// ordinal of `PetpetRsBuilder.EncodeFormat`
+fn format_ordinal(format: EncodeFormat) -> i32 {
+    match format {
+        EncodeFormat::PNG => 0,
+        EncodeFormat::GIF => 1,
+        EncodeFormat::WEBP => 2,
+        EncodeFormat::APNG => 3,
+    }
+}
+
+/// `PetpetRsBuilder.Result` with the encoded bytes and the format actually used
+fn new_result<'local>(
+    env: &mut JNIEnv<'local>,
+    blob: &[u8],
+    format: EncodeFormat,
+) -> Result<JObject<'local>, Error> {
+    let map_err = |e: jni::errors::Error| ImageEncodeError(format!("Can not create Result: {}", e));
+    let bytes = env.byte_array_from_slice(blob).map_err(map_err)?;
+    env.new_object(
+        RESULT_CLASS,
+        "([BI)V",
+        &[JValue::Object(&bytes), JValue::Int(format_ordinal(format))],
+    ).map_err(map_err)
+}
+
 #[no_mangle]
 pub extern "C" fn Java_PetpetRsBuilder_createBuilder<'local>(
     mut env: JNIEnv<'local>, _class: JClass<'local>,
@@ -37,6 +68,23 @@ pub extern "C" fn Java_PetpetRsBuilder_createBuilder<'local>(
 }
 
 
+/// `options` is a JSON string, same fields as `requester` in server config
+#[no_mangle]
+pub extern "C" fn Java_PetpetRsBuilder_setRequesterOptions<'local>(
+    mut env: JNIEnv<'local>, _class: JClass<'local>,
+    options: JString<'local>,
+) {
+    let options: String = env.get_string(&options).expect("Couldn't get java string!").into();
+    if let Err(e) = set_requester_options_by_string(options) {
+        let _ = env.throw_new("java/lang/RuntimeException", format!("{:?}", e));
+    }
+}
+
+fn set_requester_options_by_string(options: String) -> Result<(), Error> {
+    let options: RequesterOptions = serde_json::from_str(&options)?;
+    set_requester_options(options)
+}
+
 fn create_builder(template: String, path: String) -> Result<PetpetBuilder, Error> {
     let template: PetpetTemplate = serde_json::from_str(&template)?;
     PetpetBuilder::new(template, path)
@@ -91,17 +139,17 @@ pub unsafe extern "C" fn Java_PetpetRsBuilder_builderBuildByString<'local>(
     mut env: JNIEnv<'local>, class: JClass<'local>,
     ptr: *mut PetpetBuilder,
     data: JString<'local>,
-) -> JByteArray<'local> {
+) -> JObject<'local> {
     if ptr.is_null() {
         let _ = env.throw_new("java/lang/NullPointerException", "");
-        return JByteArray::default();
+        return JObject::null();
     }
 
     match builder_build_by_string(&mut env, class, ptr, data) {
-        Ok(arr) => arr,
+        Ok(result) => result,
         Err(e) => {
             let _ = env.throw_new("java/lang/RuntimeException", format!("{:?}", e));
-            JByteArray::default()
+            JObject::null()
         }
     }
 }
@@ -111,7 +159,7 @@ unsafe fn builder_build_by_string<'local>(
     env: &mut JNIEnv<'local>, _class: JClass<'local>,
     ptr: *mut PetpetBuilder,
     data: JString<'local>,
-) -> Result<JByteArray<'local>, Error> {
+) -> Result<JObject<'local>, Error> {
     let data_raw: String = env.get_string(&data).expect("Couldn't get java string!").into();
     let data: PetpetData = serde_json::from_str(&data_raw)?;
     let builder = Box::from_raw(ptr);
@@ -119,8 +167,8 @@ unsafe fn builder_build_by_string<'local>(
     let (images, delay) = RUNTIME.block_on(
         builder.build(avatar_data, data.text)
     )?;
-    let (blob, _format) = IMAGE_ENCODER.encode(&images, delay)?;
-    Ok(env.byte_array_from_slice(&blob).unwrap())
+    let (blob, format) = IMAGE_ENCODER.encode_with(&images, delay, &data.encode)?;
+    new_result(env, &blob, format)
 }
 
 
@@ -129,17 +177,18 @@ pub unsafe extern "C" fn Java_PetpetRsBuilder_builderBuildByObjects<'local>(
     mut env: JNIEnv<'local>, class: JClass<'local>,
     ptr: *mut PetpetBuilder,
     avatar_data: JObject<'local>, text_data: JObject<'local>,
-) -> JByteArray<'local> {
+    format: JString<'local>,
+) -> JObject<'local> {
     if ptr.is_null() {
         let _ = env.throw_new("java/lang/NullPointerException", "");
-        return JByteArray::default();
+        return JObject::null();
     }
 
-    match builder_build_by_objects(&mut env, class, ptr, avatar_data, text_data) {
-        Ok(arr) => arr,
+    match builder_build_by_objects(&mut env, class, ptr, avatar_data, text_data, format) {
+        Ok(result) => result,
         Err(e) => {
             let _ = env.throw_new("java/lang/RuntimeException", format!("{:?}", e));
-            JByteArray::default()
+            JObject::null()
         }
     }
 }
@@ -149,14 +198,22 @@ unsafe fn builder_build_by_objects<'local>(
     env: &mut JNIEnv<'local>, _class: JClass<'local>,
     ptr: *mut PetpetBuilder,
     avatar_data: JObject<'local>, text_data: JObject<'local>,
-) -> Result<JByteArray<'local>, Error> {
+    format: JString<'local>,
+) -> Result<JObject<'local>, Error> {
+    // `null` to decide by frame count
+    let format = if format.is_null() {
+        None
+    } else {
+        let format: String = env.get_string(&format).expect("Couldn't get java string!").into();
+        Some(EncodeFormat::from_str(&format)?)
+    };
     let avatar_data_url = {
         jni_string_option_prop!(env, avatar_data, from);
         jni_string_option_prop!(env, avatar_data, to);
         jni_string_option_prop!(env, avatar_data, group);
         jni_string_option_prop!(env, avatar_data, bot);
         jni_string_array_option_prop!(env, avatar_data, random);
-        AvatarDataURL { from, to, bot, group, random }
+        AvatarDataURL { from, to, bot, group, random, base64: None, fallback: None }
     };
     let text_data = {
         jni_string_prop!(env, text_data, from);
@@ -171,8 +228,11 @@ unsafe fn builder_build_by_objects<'local>(
     let (images, delay) = RUNTIME.block_on(
         builder.build(avatar_data, text_data)
     )?;
-    let (blob, _format) = IMAGE_ENCODER.encode(&images, delay)?;
-    Ok(env.byte_array_from_slice(&blob).unwrap())
+    let (blob, format) = IMAGE_ENCODER.encode_with(&images, delay, &EncodeOptions {
+        format,
+        ..EncodeOptions::default()
+    })?;
+    new_result(env, &blob, format)
 }
 
 
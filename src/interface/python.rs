@@ -2,6 +2,8 @@ use crate::core::builder::petpet_builder::PetpetBuilder;
 use crate::core::encoder::encoder::{EncodeFormat, IMAGE_ENCODER};
 use crate::core::errors::Error;
 use crate::core::http::avatar_data_factory::create_avatar_data;
+use crate::core::http::requester;
+use crate::core::http::requester::RequesterOptions;
 use crate::core::http::template_data::{AvatarDataURL, PetpetData};
 use crate::core::template::petpet_template::PetpetTemplate;
 use crate::core::template::text_template::TextData;
@@ -20,6 +22,8 @@ struct PyPetpetBuilder {
 enum PyOutputFormat {
     GIF,
     PNG,
+    WEBP,
+    APNG,
 }
 
 impl From<EncodeFormat> for PyOutputFormat {
@@ -27,6 +31,8 @@ impl From<EncodeFormat> for PyOutputFormat {
         match value {
             EncodeFormat::PNG => Self::PNG,
             EncodeFormat::GIF => Self::GIF,
+            EncodeFormat::WEBP => Self::WEBP,
+            EncodeFormat::APNG => Self::APNG,
         }
     }
 }
@@ -60,7 +66,7 @@ impl PyPetpetBuilder {
                 avatar_data,
                 data.text,
             ).await.unwrap();
-            let (blob, format) = IMAGE_ENCODER.encode(&images, delay).unwrap();
+            let (blob, format) = IMAGE_ENCODER.encode_with(&images, delay, &data.encode).unwrap();
 
             let bytes: Py<PyTuple> = Python::with_gil(|py|
             PyTuple::new(
@@ -75,9 +81,19 @@ impl PyPetpetBuilder {
     }
 }
 
+/// same fields as `requester` in server config
+#[pyfunction]
+fn set_requester_options(py_options: &PyAny) -> PyResult<()> {
+    let options: RequesterOptions = depythonize(py_options)
+        .map_err(|err| PyValueError::new_err(err.to_string()))?;
+    requester::set_requester_options(options)
+        .map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
 #[pymodule]
 fn petpet(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyPetpetBuilder>()?;
     m.add_class::<PyOutputFormat>()?;
+    m.add_function(wrap_pyfunction!(set_requester_options, m)?)?;
     Ok(())
 }
\ No newline at end of file
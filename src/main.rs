@@ -20,8 +20,6 @@ mod server;
 #[cfg(feature = "server")]
 async fn main() {
     let config = ServerConfig::read_or_save("./config.json");
-    // let service = PetpetService::with_paths(&config.data_path).unwrap();
-    // &service.generate_all().await;
     // let f = FontMgr::new();
     // let _: Vec<String> = dbg!(f.family_names().collect());
 
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use axum::{Json, Router};
+use axum::extract::{DefaultBodyLimit, Multipart, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::routing::{post, put};
+use serde::Serialize;
+
+use crate::core::errors::Error;
+use crate::core::errors::Error::{BadRequestError, UnauthorizedError};
+use crate::server::server::PetpetServer;
+
+static MAX_UPLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Serialize)]
+struct ReloadResult {
+    errors: Vec<String>,
+}
+
+pub fn routes() -> Router<Arc<PetpetServer>> {
+    Router::new()
+        .route("/admin/templates/:key", put(put_template).delete(delete_template))
+        .route("/admin/reload", post(reload))
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE))
+}
+
+/// multipart body, `data.json` part and frame parts named `0.png`, `1.png`...
+async fn put_template(
+    State(server): State<Arc<PetpetServer>>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+    mut multipart: Multipart,
+) -> Result<StatusCode, Error> {
+    check_token(&server, &headers)?;
+    check_key(&key)?;
+
+    let mut template: Option<String> = None;
+    let mut frames: Vec<(usize, Vec<u8>)> = Vec::new();
+    while let Some(field) = multipart.next_field().await
+        .map_err(|err| BadRequestError(err.to_string()))? {
+        let name = field.name().unwrap_or_default().to_string();
+        let bytes = field.bytes().await
+            .map_err(|err| BadRequestError(err.to_string()))?;
+        if name == "data.json" {
+            template = Some(String::from_utf8(bytes.to_vec())
+                .map_err(|_| BadRequestError("data.json is not valid UTF-8".to_string()))?);
+        } else if let Ok(i) = name.trim_end_matches(".png").parse::<usize>() {
+            frames.push((i, bytes.to_vec()));
+        } else {
+            return Err(BadRequestError(format!("Unexpected part: {}", name)));
+        }
+    }
+
+    let template = template.ok_or(BadRequestError("Missing data.json".to_string()))?;
+    frames.sort_by_key(|(i, _)| *i);
+    for (expected, (i, _)) in frames.iter().enumerate() {
+        if expected != *i {
+            return Err(BadRequestError(format!("Missing frame: {}.png", expected)));
+        }
+    }
+    let frames: Vec<Vec<u8>> = frames.into_iter().map(|(_, bytes)| bytes).collect();
+
+    let service = Arc::clone(&server.service);
+    tokio::task::spawn_blocking(move || service.save_template(&key, &template, &frames))
+        .await
+        .map_err(|err| Error::FileError(err.to_string()))??;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_template(
+    State(server): State<Arc<PetpetServer>>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<StatusCode, Error> {
+    check_token(&server, &headers)?;
+    check_key(&key)?;
+    server.service.delete_template(&key)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reload(
+    State(server): State<Arc<PetpetServer>>,
+    headers: HeaderMap,
+) -> Result<Json<ReloadResult>, Error> {
+    check_token(&server, &headers)?;
+    let errors = server.service.reload()?;
+    Ok(Json(ReloadResult {
+        errors: errors.iter().map(|err| err.to_string()).collect(),
+    }))
+}
+
+pub(crate) fn check_token(server: &PetpetServer, headers: &HeaderMap) -> Result<(), Error> {
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (&server.admin_token, token) {
+        (Some(expected), Some(token)) if expected == token => Ok(()),
+        _ => Err(UnauthorizedError("Invalid admin token".to_string())),
+    }
+}
+
+fn check_key(key: &str) -> Result<(), Error> {
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(BadRequestError(format!("Invalid template key: {}", key)));
+    }
+    Ok(())
+}
@@ -1,12 +1,36 @@
 use std::fs::File;
 use serde::{Deserialize, Serialize};
 
+use crate::core::http::requester::RequesterOptions;
+use crate::core::loader::frame_limit::frame_limit_default;
+use crate::core::template::avatar_template::AvatarFrameLimit;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     #[serde(default = "address_default")]
     pub address: String,
     #[serde(default = "data_path_default", rename="dataPath")]
     pub data_path: Vec<String>,
+    /// seconds between template directory scans, 0 to disable hot reload
+    #[serde(default = "reload_interval_default", rename="reloadInterval")]
+    pub reload_interval: u64,
+    /// `Authorization: Bearer <token>` for `/admin` routes, disabled if not set
+    #[serde(default = "admin_token_default", rename="adminToken")]
+    pub admin_token: Option<String>,
+    /// directory allowed for `file://` and path avatars, disabled if not set
+    #[serde(default = "local_avatar_root_default", rename="localAvatarRoot")]
+    pub local_avatar_root: Option<String>,
+    /// seconds to keep downloaded avatars, 0 to disable the avatar cache
+    #[serde(default = "avatar_cache_ttl_default", rename="avatarCacheTtl")]
+    pub avatar_cache_ttl: u64,
+    /// MiB of decoded avatar pixels kept in memory
+    #[serde(default = "avatar_cache_size_default", rename="avatarCacheSize")]
+    pub avatar_cache_size: usize,
+    #[serde(default = "requester_default")]
+    pub requester: RequesterOptions,
+    /// animated avatar limits for templates without their own `frameLimit`
+    #[serde(default = "frame_limit_default", rename="frameLimit")]
+    pub frame_limit: AvatarFrameLimit,
 }
 
 impl ServerConfig {
@@ -26,6 +50,13 @@ fn save_config(path: &str) -> ServerConfig {
     let default_config = ServerConfig {
         address: address_default(),
         data_path: data_path_default(),
+        reload_interval: reload_interval_default(),
+        admin_token: admin_token_default(),
+        local_avatar_root: local_avatar_root_default(),
+        avatar_cache_ttl: avatar_cache_ttl_default(),
+        avatar_cache_size: avatar_cache_size_default(),
+        requester: requester_default(),
+        frame_limit: frame_limit_default(),
     };
     let _ = serde_json::to_writer_pretty(&mut file, &default_config);
     default_config
@@ -37,4 +68,28 @@ fn address_default() -> String {
 
 fn data_path_default() -> Vec<String> {
     vec!["./data".to_string()]
+}
+
+fn reload_interval_default() -> u64 {
+    0
+}
+
+fn admin_token_default() -> Option<String> {
+    None
+}
+
+fn local_avatar_root_default() -> Option<String> {
+    None
+}
+
+fn avatar_cache_ttl_default() -> u64 {
+    300
+}
+
+fn avatar_cache_size_default() -> usize {
+    256
+}
+
+fn requester_default() -> RequesterOptions {
+    RequesterOptions::default()
 }
\ No newline at end of file
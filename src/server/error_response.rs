@@ -0,0 +1,86 @@
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use log::warn;
+use serde::Serialize;
+
+use crate::core::errors::Error;
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+impl Error {
+    /// stable error code for clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::RequestError(_) => "AVATAR_REQUEST_FAILED",
+            Error::UrlParseError(_) => "INVALID_URL",
+            Error::ImageDecodeError(_)
+            | Error::UploadDecodeError(_) => "IMAGE_DECODE_FAILED",
+            Error::ImageSynthesisError(_) => "IMAGE_SYNTHESIS_FAILED",
+            Error::ImageEncodeError(_) => "IMAGE_ENCODE_FAILED",
+            Error::FileError(_) => "FILE_ERROR",
+            Error::TemplateError(_) => "TEMPLATE_ERROR",
+            Error::AvatarLoadError(_) => "AVATAR_LOAD_FAILED",
+            Error::AvatarNotFoundError(_) => "AVATAR_NOT_FOUND",
+            Error::EvalPosError(_) => "TEMPLATE_EVAL_FAILED",
+            Error::MissingDataError(_) => "MISSING_DATA",
+            Error::NotFoundError(_) => "TEMPLATE_NOT_FOUND",
+            Error::BadRequestError(_) => "BAD_REQUEST",
+            Error::UnauthorizedError(_) => "UNAUTHORIZED",
+            Error::AvatarTooLargeError(_) => "AVATAR_TOO_LARGE",
+            Error::ContentTypeError(_) => "UNSUPPORTED_CONTENT_TYPE",
+            Error::RequestTimeoutError(_) => "AVATAR_TIMEOUT",
+            Error::UrlPolicyError(_) => "AVATAR_URL_FORBIDDEN",
+            Error::SyncPoisonError(_) => "INTERNAL_ERROR",
+            Error::SerializationError(_) => "SERIALIZATION_ERROR",
+            Error::IOError(_) => "IO_ERROR",
+            Error::SharedError(err) => err.code(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::SharedError(err) => err.status(),
+            Error::NotFoundError(_)
+            | Error::AvatarNotFoundError(_) => StatusCode::NOT_FOUND,
+            Error::UnauthorizedError(_) => StatusCode::UNAUTHORIZED,
+            Error::AvatarTooLargeError(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::ContentTypeError(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::RequestError(err) if err.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            Error::RequestTimeoutError(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::UrlPolicyError(_) => StatusCode::FORBIDDEN,
+            Error::MissingDataError(_)
+            | Error::BadRequestError(_)
+            | Error::UploadDecodeError(_)
+            | Error::UrlParseError(_) => StatusCode::BAD_REQUEST,
+            Error::TemplateError(_)
+            | Error::EvalPosError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::RequestError(_)
+            | Error::AvatarLoadError(_)
+            | Error::ImageDecodeError(_) => StatusCode::BAD_GATEWAY,
+            Error::ImageSynthesisError(_)
+            | Error::ImageEncodeError(_)
+            | Error::FileError(_)
+            | Error::SyncPoisonError(_)
+            | Error::SerializationError(_)
+            | Error::IOError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            warn!("{}", self);
+        }
+        (status, Json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+        })).into_response()
+    }
+}
@@ -3,5 +3,11 @@ pub mod server;
 pub mod service;
 #[cfg(feature = "server")]
 pub mod config;
+#[cfg(feature = "server")]
+mod error_response;
+#[cfg(feature = "server")]
+mod admin;
+#[cfg(feature = "server")]
+mod multipart_data;
 
 mod query_template;
\ No newline at end of file
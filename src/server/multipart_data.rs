@@ -0,0 +1,45 @@
+use axum::extract::Multipart;
+
+use crate::core::errors::Error;
+use crate::core::errors::Error::{BadRequestError, MissingDataError};
+use crate::core::http::template_data::AvatarDataBlob;
+use crate::server::service::service_data::PetpetServiceData;
+
+/// `data` part is the same JSON as POST body (or a plain `key` field),
+/// image parts `from`, `to`, `bot`, `group`, `random[]` are mixed with avatar urls
+pub async fn from_multipart(mut multipart: Multipart) -> Result<(PetpetServiceData, AvatarDataBlob), Error> {
+    let mut data: Option<PetpetServiceData> = None;
+    let mut key: Option<String> = None;
+    let mut blob = AvatarDataBlob::default();
+
+    while let Some(field) = multipart.next_field().await
+        .map_err(|err| BadRequestError(err.to_string()))? {
+        let name = field.name().unwrap_or_default().to_string();
+        let bytes = field.bytes().await
+            .map_err(|err| BadRequestError(err.to_string()))?
+            .to_vec();
+        match name.as_str() {
+            "data" => data = Some(serde_json::from_slice(&bytes)
+                .map_err(|err| BadRequestError(format!("Can not decode data: {}", err)))?),
+            "key" => key = Some(String::from_utf8(bytes)
+                .map_err(|_| BadRequestError("key is not valid UTF-8".to_string()))?),
+            "from" => blob.from = Some(bytes),
+            "to" => blob.to = Some(bytes),
+            "bot" => blob.bot = Some(bytes),
+            "group" => blob.group = Some(bytes),
+            "random" | "random[]" => blob.random.push(bytes),
+            _ => return Err(BadRequestError(format!("Unexpected part: {}", name))),
+        }
+    }
+
+    let mut data: PetpetServiceData = match data {
+        Some(data) => data,
+        None => serde_json::from_value(serde_json::json!({
+            "key": key.clone().ok_or(MissingDataError("Missing data or key part".to_string()))?
+        }))?,
+    };
+    if let Some(key) = key {
+        data.key = key;
+    }
+    Ok((data, blob))
+}
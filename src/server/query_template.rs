@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 
+use crate::core::encoder::encoder::{EncodeFormat, EncodeOptions};
 use crate::core::http::template_data::{AvatarDataURL};
 use crate::core::template::text_template::TextData;
 use crate::server::service::service_data::PetpetServiceData;
@@ -23,6 +24,10 @@ pub struct QueryParams {
     pub group_name: String,
     #[serde(rename = "textList", default = "text_list_default")]
     pub text_list: String,
+    pub format: Option<EncodeFormat>,
+    pub quality: Option<u32>,
+    pub speed: Option<i32>,
+    pub optimize: Option<bool>,
 }
 
 impl QueryParams {
@@ -35,6 +40,8 @@ impl QueryParams {
                 bot: self.bot_avatar,
                 group: self.group_avatar,
                 random: None,
+                base64: None,
+                fallback: None,
             },
             text: TextData {
                 from: self.from_name,
@@ -43,6 +50,12 @@ impl QueryParams {
                 text_list: self.text_list.split_whitespace()
                     .map(|s| s.to_owned()).collect(),
             },
+            encode: EncodeOptions {
+                format: self.format,
+                quality: self.quality,
+                speed: self.speed,
+                optimize: self.optimize,
+            },
         }
     }
 }
@@ -1,25 +1,44 @@
 use core::str::FromStr;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use axum::{http::StatusCode, Json, Router, routing::get, routing::post};
-use axum::extract::{Query, State};
-use axum::http::header;
-use axum::response::IntoResponse;
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, FromRequest, Multipart, Path, Query, State};
+use axum::extract::rejection::QueryRejection;
+use axum::http::{header, HeaderMap, HeaderValue, Request};
+use axum::response::{IntoResponse, Response};
 use log::info;
-use serde::Serialize;
-use crate::core::encoder::encoder::IMAGE_ENCODER;
+use serde::{Deserialize, Serialize};
+use crate::core::builder::petpet_builder::PetpetBuilder;
+use crate::core::encoder::encoder::{EncodeFormat, IMAGE_ENCODER};
 use crate::core::errors::Error;
-use crate::core::http::avatar_data_factory::create_avatar_data;
+use crate::core::errors::Error::{BadRequestError, NotFoundError};
+use crate::core::http::avatar_cache::{AvatarCacheOptions, set_avatar_cache_options};
+use crate::core::http::avatar_data_factory::create_avatar_data_with_blob;
+use crate::core::http::local_file::set_local_avatar_root;
+use crate::core::http::requester::set_requester_options;
+use crate::core::loader::frame_limit::set_frame_limit;
+use crate::core::http::template_data::AvatarDataBlob;
+use crate::core::model::text_model::text_variables;
+use crate::server::admin;
 use crate::server::config::ServerConfig;
+use crate::server::multipart_data::from_multipart;
 use crate::server::query_template::QueryParams;
 use crate::server::service::petpet_service::PetpetService;
 use crate::server::service::service_data::PetpetServiceData;
 
+static MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+static PREVIEW_SIZE_DEFAULT: i32 = 128;
+static PREVIEW_SIZE_MAX: i32 = 512;
+
 pub struct PetpetServer {
     addr: SocketAddr,
-    pub(crate) service: PetpetService,
+    reload_interval: u64,
+    pub(crate) admin_token: Option<String>,
+    pub(crate) service: Arc<PetpetService>,
 }
 
 #[derive(Serialize)]
@@ -31,23 +50,53 @@ struct ServerInfo {
 #[derive(Serialize)]
 struct TemplateInfo {
     id: String,
+    #[serde(rename = "type")]
+    _type: String,
     avatar: TemplateItemInfo,
-    text: TemplateItemInfo,
+    text: TextItemInfo,
     alias: Vec<String>,
+    frames: usize,
+    /// `None` if canvas size depends on avatar or text
+    size: Option<(i32, i32)>,
+    /// hidden templates are only listed with the admin token
+    hidden: bool,
+    #[serde(rename = "inRandomList")]
+    in_random_list: bool,
 }
 
 #[derive(Serialize)]
 struct TemplateItemInfo {
     types: Vec<String>,
-    length: i32,
+    /// avatar slots, or `textList` entries needed
+    length: usize,
+    /// avatar slots per type
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    count: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize)]
+struct TextItemInfo {
+    /// variables used by each text, in template order
+    types: Vec<Vec<String>>,
+    /// `textList` entries needed
+    length: usize,
 }
 
 
 impl PetpetServer {
     pub fn new(config: ServerConfig) -> Result<Self, Error> {
+        set_requester_options(config.requester.clone())?;
+        set_local_avatar_root(config.local_avatar_root.as_deref())?;
+        set_frame_limit(config.frame_limit.clone())?;
+        set_avatar_cache_options(AvatarCacheOptions {
+            ttl: Duration::from_secs(config.avatar_cache_ttl),
+            max_size: config.avatar_cache_size * 1024 * 1024,
+        })?;
         Ok(PetpetServer {
             addr: SocketAddr::from_str(&config.address).unwrap(),
-            service: PetpetService::with_paths(&config.data_path)?,
+            reload_interval: config.reload_interval,
+            admin_token: config.admin_token,
+            service: Arc::new(PetpetService::with_paths(&config.data_path)?),
         })
     }
 
@@ -55,12 +104,19 @@ impl PetpetServer {
         tracing_subscriber::fmt::init();
 
         let addr = self.addr.clone();
+        if self.reload_interval != 0 {
+            Arc::clone(&self.service).watch(Duration::from_secs(self.reload_interval));
+        }
 
-        let app = Router::new()
+        let mut app = Router::new()
             .route("/", get(get_info))
-            .route("/generate", post(generate_post))
+            .route("/generate", post(generate_post).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)))
             .route("/generate", get(generate_get))
-            .with_state(Arc::new(self));
+            .route("/preview/:key", get(get_preview));
+        if self.admin_token.is_some() {
+            app = app.merge(admin::routes());
+        }
+        let app = app.with_state(Arc::new(self));
 
         info!("server run in {}", &addr);
         axum::Server::bind(&addr)
@@ -71,59 +127,151 @@ impl PetpetServer {
 
 async fn get_info(
     State(server): State<Arc<PetpetServer>>,
+    headers: HeaderMap,
 ) -> (StatusCode, Json<ServerInfo>) {
+    let show_hidden = admin::check_token(&server, &headers).is_ok();
     let info = ServerInfo {
-        //TODO
-        version: "0.0.1-dev".to_string(),
-        templates: server.service.builder_map.iter()
-            .map(|(k, v)| {
-                let mut avatar_types: Vec<String> = v.template.avatar.iter()
-                    .map(|avatar| format!("{:?}", avatar._type))
-                    .collect();
-                avatar_types.sort();
-                avatar_types.dedup();
-                return TemplateInfo {
-                    id: k.clone(),
-                    avatar: TemplateItemInfo {
-                        types: avatar_types,
-                        length: 0, //TODO
-                    },
-                    text: TemplateItemInfo { //TODO
-                        types: vec![],
-                        length: 0,
-                    },
-                    alias: v.template.alias.clone(),
-                };
-            }).collect(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        templates: server.service.builders().iter()
+            .filter(|(_, v)| show_hidden || !v.template.hidden)
+            .map(|(k, v)| template_info(k, v))
+            .collect(),
     };
     (StatusCode::OK, Json(info))
 }
 
+fn template_info(key: &str, builder: &PetpetBuilder) -> TemplateInfo {
+    let template = &builder.template;
+    let mut avatar_count: BTreeMap<String, usize> = BTreeMap::new();
+    for avatar in &template.avatar {
+        *avatar_count.entry(format!("{:?}", avatar._type)).or_insert(0) += 1;
+    }
+
+    let text_types: Vec<Vec<String>> = template.text.iter()
+        .map(|text| {
+            let mut types = text_variables(&text.text);
+            types.sort();
+            types.dedup();
+            types
+        })
+        .collect();
+    let text_length = text_types.iter()
+        .flatten()
+        .filter_map(|var| var.strip_prefix("$txt")?.parse::<usize>().ok())
+        .max()
+        .unwrap_or(0);
+
+    TemplateInfo {
+        id: key.to_string(),
+        _type: format!("{:?}", template._type),
+        avatar: TemplateItemInfo {
+            types: avatar_count.keys().cloned().collect(),
+            length: template.avatar.len(),
+            count: avatar_count,
+        },
+        text: TextItemInfo {
+            types: text_types,
+            length: text_length,
+        },
+        alias: template.alias.clone(),
+        frames: builder.frame_count(),
+        size: builder.canvas_size(),
+        hidden: template.hidden,
+        in_random_list: template.in_random_list,
+    }
+}
+
+#[derive(Deserialize)]
+struct PreviewParams {
+    size: Option<i32>,
+}
+
+async fn get_preview(
+    State(server): State<Arc<PetpetServer>>,
+    Path(key): Path<String>,
+    params: Result<Query<PreviewParams>, QueryRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Query(params) = params.map_err(|err| BadRequestError(err.body_text()))?;
+    let size = params.size.unwrap_or(PREVIEW_SIZE_DEFAULT).clamp(16, PREVIEW_SIZE_MAX);
+    let (blob, format) = server.service.preview(&key, size).await?;
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, format.to_format())],
+        blob.as_ref().clone(),
+    ))
+}
+
+/// JSON body, or multipart with uploaded avatar images
 async fn generate_post(
     State(server): State<Arc<PetpetServer>>,
-    Json(payload): Json<PetpetServiceData>,
-) -> impl IntoResponse {
-    let avatar_data = create_avatar_data(&payload.avatar).unwrap();
-    let builder = server.service.get_builder(&payload.key).unwrap();
-    let start_time0 = Instant::now();
-    let (images, delay) = builder.build(avatar_data, payload.text).await.unwrap();
-    let start_time1 = Instant::now();
-    let (blob, format) = IMAGE_ENCODER.encode(&images, delay).unwrap();
-    info!("template: {}; download & draw: {:?}; encode: {:?}", &payload.key, start_time0.elapsed(), start_time1.elapsed());
-    (StatusCode::OK, [(header::CONTENT_TYPE, format.to_format())], blob)
+    request: Request<Body>,
+) -> Result<impl IntoResponse, Error> {
+    let headers = request.headers().clone();
+    let is_multipart = headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("multipart/form-data"));
+    if is_multipart {
+        let multipart = Multipart::from_request(request, &server).await
+            .map_err(|err| BadRequestError(err.body_text()))?;
+        let (payload, blob) = from_multipart(multipart).await?;
+        generate(&server, &headers, payload, blob).await
+    } else {
+        let Json(payload) = Json::<PetpetServiceData>::from_request(request, &server).await
+            .map_err(|err| BadRequestError(err.body_text()))?;
+        generate(&server, &headers, payload, AvatarDataBlob::default()).await
+    }
 }
 
 async fn generate_get(
     State(server): State<Arc<PetpetServer>>,
-    Query(payload): Query<QueryParams>,
-) -> impl IntoResponse {
-    let data = payload.to_data();
-    let avatar_data = create_avatar_data(&data.avatar).unwrap();
-    let builder = server.service.get_builder(&data.key).unwrap();
+    headers: HeaderMap,
+    payload: Result<Query<QueryParams>, QueryRejection>,
+) -> Result<impl IntoResponse, Error> {
+    let Query(payload) = payload.map_err(|err| BadRequestError(err.body_text()))?;
+    generate(&server, &headers, payload.to_data(), AvatarDataBlob::default()).await
+}
+
+async fn generate(
+    server: &PetpetServer,
+    headers: &HeaderMap,
+    mut data: PetpetServiceData,
+    blob: AvatarDataBlob,
+) -> Result<Response, Error> {
+    let negotiated = data.encode.format.is_none();
+    if negotiated {
+        data.encode.format = accept_format(headers);
+    }
+    let builder = server.service.get_builder(&data.key)
+        .ok_or_else(|| NotFoundError(format!("Can not find template: {}", &data.key)))?;
+    let avatar_data = create_avatar_data_with_blob(&data.avatar, blob)?;
     let start_time0 = Instant::now();
-    let (images, delay) = builder.build(avatar_data, data.text).await.unwrap();
+    let (images, delay) = builder.build(avatar_data, data.text).await?;
     let start_time1 = Instant::now();
-    let (blob, format) = IMAGE_ENCODER.encode(&images, delay).unwrap();
+    let (blob, format) = IMAGE_ENCODER.encode_with(&images, delay, &data.encode)?;
     info!("template: {}; download & draw: {:?}; encode: {:?}", &data.key, start_time0.elapsed(), start_time1.elapsed());
-    (StatusCode::OK, [(header::CONTENT_TYPE, format.to_format())], blob)
+    let mut response = (StatusCode::OK, [(header::CONTENT_TYPE, format.to_format())], blob).into_response();
+    if negotiated {
+        // the body depends on `Accept`, caches must not serve it to other clients
+        response.headers_mut().insert(header::VARY, HeaderValue::from_static("accept"));
+    }
+    Ok(response)
+}
+
+/// pick the preferred supported format from `Accept` header, ignore wildcards
+fn accept_format(headers: &HeaderMap) -> Option<EncodeFormat> {
+    let accept = headers.get(header::ACCEPT)?.to_str().ok()?;
+    let mut formats: Vec<(EncodeFormat, f32)> = accept.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let format = EncodeFormat::from_mime(parts.next()?)?;
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((format, q))
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect();
+    formats.sort_by(|a, b| b.1.total_cmp(&a.1));
+    formats.first().map(|(format, _)| *format)
 }
@@ -1,2 +1,3 @@
 pub mod petpet_service;
-pub mod service_data;
\ No newline at end of file
+pub mod service_data;
+mod preview;
\ No newline at end of file
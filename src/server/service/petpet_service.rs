@@ -1,65 +1,116 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use rand::Rng;
+use schnellru::{ByLength, LruMap};
 
 use crate::core::builder::petpet_builder::PetpetBuilder;
-use crate::core::encoder::encoder::IMAGE_ENCODER;
+use crate::core::encoder::encoder::{EncodeFormat, IMAGE_ENCODER};
 use crate::core::errors::Error;
-use crate::core::errors::Error::{FileError, TemplateError};
-use crate::core::http::avatar_data_factory::create_avatar_data;
-use crate::core::http::template_data::AvatarDataURL;
+use crate::core::errors::Error::{FileError, NotFoundError, TemplateError};
+use crate::core::loader::image_loader::{invalidate_cached_background, load_image};
 use crate::core::template::petpet_template::PetpetTemplate;
 use crate::core::template::text_template::TextData;
+use crate::server::service::preview::{placeholder_avatar_data, thumbnail};
+
+/// picks a template with `inRandomList: true`
+static RANDOM_KEY: &str = "random";
+static MAX_FUZZY_DISTANCE: usize = 2;
+static MAX_PREVIEW_CACHE_LENGTH: u32 = 256;
+
+/// the builder it was rendered from, encoded blob and format
+type CachedPreview = (Weak<PetpetBuilder>, Arc<Vec<u8>>, EncodeFormat);
+
+/// file count and latest modified time of a template directory
+type Fingerprint = (usize, Option<SystemTime>);
 
 pub struct PetpetService {
-    pub builder_map: HashMap<String, PetpetBuilder>,
+    pub builder_map: RwLock<HashMap<String, Arc<PetpetBuilder>>>,
+    paths: Vec<String>,
+    fingerprints: Mutex<HashMap<String, (String, Fingerprint)>>,
+    previews: Mutex<LruMap<(String, i32), CachedPreview, ByLength>>,
+    /// suffix of staging directories, unique per upload
+    uploads: AtomicU64,
+    /// held while a validated upload replaces the template directory
+    save_lock: Mutex<()>,
 }
 
 impl PetpetService {
     pub fn new() -> PetpetService {
         PetpetService {
-            builder_map: HashMap::with_capacity(32)
+            builder_map: RwLock::new(HashMap::with_capacity(32)),
+            paths: Vec::new(),
+            fingerprints: Mutex::new(HashMap::with_capacity(32)),
+            previews: Mutex::new(LruMap::new(ByLength::new(MAX_PREVIEW_CACHE_LENGTH))),
+            uploads: AtomicU64::new(0),
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    /// resolve by exact key, then `random`, then case-insensitive key or alias, then fuzzy match.
+    /// hidden templates can only be resolved by exact key
+    pub fn get_builder(&self, key: &str) -> Option<Arc<PetpetBuilder>> {
+        let map = self.builder_map.read().ok()?;
+        if let Some(builder) = map.get(key) {
+            return Some(Arc::clone(builder));
+        }
+
+        if key.eq_ignore_ascii_case(RANDOM_KEY) {
+            let random_list: Vec<&Arc<PetpetBuilder>> = map.values()
+                .filter(|builder| !builder.template.hidden && builder.template.in_random_list)
+                .collect();
+            if random_list.is_empty() {
+                return None;
+            }
+            let index = rand::thread_rng().gen_range(0..random_list.len());
+            return Some(Arc::clone(random_list[index]));
+        }
+
+        let key = key.to_lowercase();
+        // ties go to the smallest template key, so the result does not depend on map order
+        let mut best: Option<(usize, &String, &Arc<PetpetBuilder>)> = None;
+        for (k, builder) in map.iter().filter(|(_, builder)| !builder.template.hidden) {
+            let names = std::iter::once(k).chain(builder.template.alias.iter());
+            for name in names {
+                let distance = edit_distance(&key, &name.to_lowercase());
+                if best.map_or(true, |(d, bk, _)| (distance, k) < (d, bk)) {
+                    best = Some((distance, k, builder));
+                }
+            }
         }
+        let max_distance = usize::min(MAX_FUZZY_DISTANCE, key.chars().count() / 3);
+        best.filter(|(distance, _, _)| *distance <= max_distance)
+            .map(|(_, _, builder)| Arc::clone(builder))
     }
 
-    pub fn get_builder(&self, key: &str) -> Option<&PetpetBuilder> {
-        self.builder_map.get(key)
+    /// snapshot of all loaded templates
+    pub fn builders(&self) -> Vec<(String, Arc<PetpetBuilder>)> {
+        match self.builder_map.read() {
+            Ok(map) => map.iter()
+                .map(|(k, v)| (k.clone(), Arc::clone(v)))
+                .collect(),
+            Err(_) => Vec::new()
+        }
     }
 
     pub fn join_path<'a>(&'a mut self, path: &'a str) -> Result<&Self, Error> {
         if let Ok(paths) = std::fs::read_dir(path) {
             for entry in paths {
                 let path_buf = entry?.path();
-                if !&path_buf.is_dir() {
+                if !&path_buf.is_dir() || is_hidden(&path_buf) {
                     continue
                 }
-                let root_path_str = (&path_buf).to_str().ok_or(
-                    FileError(format!("Can not get path name: {:?}", path_buf))
-                )?.to_string();
-                let data_path_str = format!("{}/data.json", root_path_str);
-                let template: PetpetTemplate = if Path::new(&data_path_str).exists() {
-                    let str = std::fs::read_to_string(data_path_str)?;
-                    let jd = &mut serde_json::Deserializer::from_str(&str);
-                    serde_path_to_error::deserialize(jd).map_err(|err|
-                        TemplateError(format!(
-                            "Can not decode {} in {}/data.json: {}",
-                            err.path(), root_path_str, err.inner().to_string()
-                        ))
-                    )?
-                } else {
-                    continue
-                };
-                self.builder_map.insert(
-                    (&path_buf).file_name().ok_or(
-                        FileError(format!("Can not get path name: {:?}", &path_buf))
-                    )?.to_str().ok_or(
-                        FileError(format!("Can not filename convert to str: {:?}", &path_buf))
-                    )?.to_string(),
-                    PetpetBuilder::new(template, root_path_str)?
-                );
+                let fingerprint = dir_fingerprint(&path_buf);
+                if let Some((key, root_path_str, builder)) = load_template(&path_buf)? {
+                    self.fingerprints.lock()?.insert(key.clone(), (root_path_str, fingerprint));
+                    self.builder_map.write()?.insert(key, Arc::new(builder));
+                }
             }
+            self.paths.push(path.to_string());
             Ok(self)
         } else {
             Err(FileError(format!("Can not read file: {}", path)))
@@ -74,27 +125,333 @@ impl PetpetService {
         Ok(s)
     }
 
-    pub async fn generate_all(&self) {
-        let avatar = Some("https://avatars.githubusercontent.com/u/68615161?v=4".to_string());
-        for (k, v) in &self.builder_map {
-            println!("{}", k);
-            let b = &AvatarDataURL {
-                from: avatar.clone(),
-                to: avatar.clone(),
-                bot: avatar.clone(),
-                group: avatar.clone(),
-                random: None,
+    /// re-parse changed template directories,
+    /// a template that fails to parse keeps its previous version
+    pub fn reload(&self) -> Result<Vec<Error>, Error> {
+        let mut errors = Vec::new();
+        let mut found: Vec<String> = Vec::new();
+        for path in &self.paths {
+            let entries = match std::fs::read_dir(path) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    errors.push(FileError(format!("Can not read file: {}: {}", path, err)));
+                    continue
+                }
             };
-            let t = TextData::default();
-            let data = create_avatar_data(b).unwrap();
-            let start_time0 = Instant::now();
-            let (images, delay) = v.build(data, t).await.unwrap();
-            println!("download & draw: {:?}", start_time0.elapsed());
-            let start_time1 = Instant::now();
-            let (blob, format) = IMAGE_ENCODER.encode(&images, delay).unwrap();
-            println!("encode: {:?}", start_time1.elapsed());
-            let mut file = File::create(format!("./output/{}.{}", k, format.get_str())).unwrap();
-            file.write_all(&blob).expect("TODO: panic message");
-        }
-    }
-}
\ No newline at end of file
+            for entry in entries {
+                let path_buf = entry?.path();
+                if !path_buf.is_dir() || is_hidden(&path_buf) || !path_buf.join("data.json").exists() {
+                    continue
+                }
+                let key = match template_key(&path_buf) {
+                    Ok(key) => key,
+                    Err(err) => {
+                        errors.push(err);
+                        continue
+                    }
+                };
+                found.push(key.clone());
+
+                let fingerprint = dir_fingerprint(&path_buf);
+                let changed = match self.fingerprints.lock()?.get(&key) {
+                    Some((_, old)) => *old != fingerprint,
+                    None => true,
+                };
+                if !changed {
+                    continue
+                }
+
+                // updated even on failure so a broken template is reported once
+                let root_path_str = path_str(&path_buf)?;
+                self.fingerprints.lock()?.insert(key.clone(), (root_path_str, fingerprint));
+                // the old builder and its cached backgrounds stay until the new one is built
+                match load_template(&path_buf) {
+                    Ok(Some((key, root_path_str, builder))) => {
+                        info!("reload template: {}", &key);
+                        // under the map lock, so no request sees the new builder with stale backgrounds
+                        let mut map = self.builder_map.write()?;
+                        invalidate_cached_background(&root_path_str)?;
+                        map.insert(key, Arc::new(builder));
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        warn!("can not reload template {}: {}", &key, err);
+                        errors.push(err);
+                    }
+                }
+            }
+        }
+
+        let removed: Vec<(String, String)> = self.fingerprints.lock()?.iter()
+            .filter(|(key, _)| !found.contains(*key))
+            .map(|(key, (root_path_str, _))| (key.clone(), root_path_str.clone()))
+            .collect();
+        for (key, root_path_str) in removed {
+            info!("remove template: {}", &key);
+            invalidate_cached_background(&root_path_str)?;
+            self.fingerprints.lock()?.remove(&key);
+            self.builder_map.write()?.remove(&key);
+        }
+        Ok(errors)
+    }
+
+    pub fn template_dir(&self, key: &str) -> Option<String> {
+        self.fingerprints.lock().ok()?.get(key).map(|(dir, _)| dir.clone())
+    }
+
+    /// create or replace a template, `frames` are PNG files `0.png`, `1.png`...
+    /// the bundle is validated in a staging directory before it replaces the old one
+    pub fn save_template(&self, key: &str, template: &str, frames: &Vec<Vec<u8>>) -> Result<(), Error> {
+        let root = match self.template_dir(key) {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(self.paths.first().ok_or(
+                FileError("No data path configured".to_string())
+            )?).join(key),
+        };
+        let parent = root.parent().ok_or(
+            FileError(format!("Can not get parent path: {:?}", root))
+        )?;
+        let id = self.uploads.fetch_add(1, Ordering::Relaxed);
+        let staging = parent.join(format!(".{}.upload.{}", key, id));
+        let backup = parent.join(format!(".{}.old.{}", key, id));
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)?;
+
+        let validated: Result<(), Error> = (|| {
+            std::fs::write(staging.join("data.json"), template)?;
+            for (i, frame) in frames.iter().enumerate() {
+                std::fs::write(staging.join(format!("{}.png", i)), frame)?;
+            }
+            load_template(&staging)?;
+            for i in 0..frames.len() {
+                load_image(format!("{}/{}.png", path_str(&staging)?, i))?;
+            }
+            Ok(())
+        })();
+        if let Err(err) = validated {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(err);
+        }
+
+        let _guard = self.save_lock.lock()?;
+        if root.exists() {
+            if backup.exists() {
+                std::fs::remove_dir_all(&backup)?;
+            }
+            std::fs::rename(&root, &backup)?;
+        }
+        std::fs::rename(&staging, &root)?;
+        if backup.exists() {
+            std::fs::remove_dir_all(&backup)?;
+        }
+        self.reload()?;
+        Ok(())
+    }
+
+    pub fn delete_template(&self, key: &str) -> Result<(), Error> {
+        let root = self.template_dir(key).ok_or_else(||
+            NotFoundError(format!("Can not find template: {}", key))
+        )?;
+        std::fs::remove_dir_all(&root)?;
+        self.reload()?;
+        Ok(())
+    }
+
+    /// poll template directories in a background thread
+    pub fn watch(self: Arc<Self>, interval: Duration) {
+        let service = self;
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Err(err) = service.reload() {
+                warn!("reload templates failed: {}", err);
+            }
+        });
+    }
+
+    /// template rendered with placeholder avatars and default text,
+    /// scaled to fit in `max_size`, cached until the template is reloaded
+    pub async fn preview(&self, key: &str, max_size: i32) -> Result<(Arc<Vec<u8>>, EncodeFormat), Error> {
+        let builder = self.builder_map.read()?.get(key).cloned().ok_or_else(||
+            NotFoundError(format!("Can not find template: {}", key))
+        )?;
+        if let Some(cached) = self.previews.lock()?.get(&(key.to_string(), max_size)) {
+            if Weak::ptr_eq(&cached.0, &Arc::downgrade(&builder)) {
+                return Ok((Arc::clone(&cached.1), cached.2));
+            }
+        }
+
+        let (images, delay) = builder.build(placeholder_avatar_data(), TextData::default()).await?;
+        let (blob, format) = IMAGE_ENCODER.encode(&thumbnail(&images, max_size)?, delay)?;
+        let blob = Arc::new(blob);
+        self.previews.lock()?.insert(
+            (key.to_string(), max_size),
+            (Arc::downgrade(&builder), Arc::clone(&blob), format),
+        );
+        Ok((blob, format))
+    }
+}
+
+fn path_str(path_buf: &PathBuf) -> Result<String, Error> {
+    Ok(path_buf.to_str().ok_or(
+        FileError(format!("Can not get path name: {:?}", path_buf))
+    )?.to_string())
+}
+
+fn template_key(path_buf: &PathBuf) -> Result<String, Error> {
+    Ok(path_buf.file_name().ok_or(
+        FileError(format!("Can not get path name: {:?}", path_buf))
+    )?.to_str().ok_or(
+        FileError(format!("Can not filename convert to str: {:?}", path_buf))
+    )?.to_string())
+}
+
+/// returns `None` if the directory has no `data.json`
+fn load_template(path_buf: &PathBuf) -> Result<Option<(String, String, PetpetBuilder)>, Error> {
+    let root_path_str = path_str(path_buf)?;
+    let data_path_str = format!("{}/data.json", root_path_str);
+    if !Path::new(&data_path_str).exists() {
+        return Ok(None)
+    }
+    let str = std::fs::read_to_string(data_path_str)?;
+    let jd = &mut serde_json::Deserializer::from_str(&str);
+    let template: PetpetTemplate = serde_path_to_error::deserialize(jd).map_err(|err|
+        TemplateError(format!(
+            "Can not decode {} in {}/data.json: {}",
+            err.path(), root_path_str, err.inner().to_string()
+        ))
+    )?;
+    let key = template_key(path_buf)?;
+    let builder = PetpetBuilder::new(template, root_path_str.clone())?;
+    Ok(Some((key, root_path_str, builder)))
+}
+
+/// Levenshtein distance by chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current[j + 1] = (prev[j] + cost)
+                .min(prev[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut current);
+    }
+    prev[b.len()]
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| name.starts_with('.'))
+}
+
+fn dir_fingerprint(path: &Path) -> Fingerprint {
+    let mut count = 0;
+    let mut modified: Option<SystemTime> = None;
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.flatten() {
+            count += 1;
+            if let Ok(time) = entry.metadata().and_then(|m| m.modified()) {
+                modified = Some(modified.map_or(time, |m| m.max(time)));
+            }
+        }
+    }
+    (count, modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(templates: &[(&str, &str)]) -> PetpetService {
+        let service = PetpetService::new();
+        for (key, json) in templates {
+            let template: PetpetTemplate = serde_json::from_str(json).unwrap();
+            let builder = PetpetBuilder::new(template, String::new()).unwrap();
+            service.builder_map.write().unwrap().insert(key.to_string(), Arc::new(builder));
+        }
+        service
+    }
+
+    fn resolve(service: &PetpetService, key: &str) -> Option<String> {
+        let builder = service.get_builder(key)?;
+        service.builders().into_iter()
+            .find(|(_, b)| Arc::ptr_eq(b, &builder))
+            .map(|(k, _)| k)
+    }
+
+    #[test]
+    fn edit_distance_by_chars() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", "abc"), 0);
+        assert_eq!(edit_distance("摸摸头", "摸头"), 1);
+    }
+
+    #[test]
+    fn get_builder_by_key_alias_and_fuzzy() {
+        let service = service(&[
+            ("petpet", r##"{"type": "GIF", "background": {"size": [10, 10]}, "alias": ["摸摸", "Pat"]}"##),
+            ("kiss", r##"{"type": "IMG", "background": {"size": [10, 10]}}"##),
+            ("secret", r##"{"type": "IMG", "background": {"size": [10, 10]}, "alias": ["hush"], "hidden": true}"##),
+        ]);
+        assert_eq!(resolve(&service, "petpet").as_deref(), Some("petpet"));
+        assert_eq!(resolve(&service, "PetPet").as_deref(), Some("petpet"));
+        assert_eq!(resolve(&service, "pat").as_deref(), Some("petpet"));
+        assert_eq!(resolve(&service, "摸摸").as_deref(), Some("petpet"));
+        // one typo per three chars, at most two
+        assert_eq!(resolve(&service, "pepet").as_deref(), Some("petpet"));
+        assert_eq!(resolve(&service, "kis").as_deref(), Some("kiss"));
+        assert_eq!(resolve(&service, "ki"), None);
+        assert_eq!(resolve(&service, "potato"), None);
+        // hidden templates only by exact key
+        assert_eq!(resolve(&service, "secret").as_deref(), Some("secret"));
+        assert_eq!(resolve(&service, "Secret"), None);
+        assert_eq!(resolve(&service, "hush"), None);
+    }
+
+    #[test]
+    fn get_builder_breaks_ties_by_key() {
+        let service = service(&[
+            ("slap", r##"{"type": "IMG", "background": {"size": [10, 10]}, "alias": ["hit"]}"##),
+            ("punch", r##"{"type": "IMG", "background": {"size": [10, 10]}, "alias": ["hit"]}"##),
+            ("bonk", r##"{"type": "IMG", "background": {"size": [10, 10]}}"##),
+            ("honk", r##"{"type": "IMG", "background": {"size": [10, 10]}}"##),
+        ]);
+        assert_eq!(resolve(&service, "hit").as_deref(), Some("punch"));
+        assert_eq!(resolve(&service, "tonk").as_deref(), Some("bonk"));
+    }
+
+    #[test]
+    fn concurrent_saves_of_one_key() {
+        let dir = std::env::temp_dir().join(format!("petpet-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut service = PetpetService::new();
+        service.join_path(dir.to_str().unwrap()).unwrap();
+        let service = Arc::new(service);
+
+        let handles: Vec<_> = (0..8).map(|i| {
+            let service = Arc::clone(&service);
+            std::thread::spawn(move || {
+                let template = format!(r##"{{"type": "IMG", "background": {{"size": [{}, 10]}}}}"##, 10 + i);
+                service.save_template("petpet", &template, &Vec::new())
+            })
+        }).collect();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+
+        assert!(service.get_builder("petpet").is_some());
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec!["petpet"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
+use skia_safe::{Color, FilterMode, Image, MipmapMode, Paint, Rect, SamplingOptions};
+
+use crate::core::builder::avatar_builder::{AvatarData, AvatarDataItem, AvatarFrames};
+use crate::core::errors::Error;
+use crate::core::errors::Error::ImageSynthesisError;
+use crate::core::loader::placeholder::placeholder_image;
+
+static PLACEHOLDERS: Lazy<[Arc<Vec<Image>>; 4]> = Lazy::new(|| [
+    Arc::new(vec![placeholder_image(Color::from_rgb(0x4a, 0x90, 0xe2))]),
+    Arc::new(vec![placeholder_image(Color::from_rgb(0xf5, 0xa6, 0x23))]),
+    Arc::new(vec![placeholder_image(Color::from_rgb(0x7e, 0xd3, 0x21))]),
+    Arc::new(vec![placeholder_image(Color::from_rgb(0xbd, 0x10, 0xe0))]),
+]);
+
+/// distinct local images for `from`, `to`, `bot`, `group` and `random`
+pub fn placeholder_avatar_data<'a>() -> AvatarData<'a> {
+    AvatarData {
+        from: Some(placeholder_item(0)),
+        to: Some(placeholder_item(1)),
+        bot: Some(placeholder_item(2)),
+        group: Some(placeholder_item(3)),
+        random: (0..4).map(placeholder_item).collect(),
+        fallback: None,
+    }
+}
+
+fn placeholder_item<'a>(index: usize) -> AvatarDataItem<'a> {
+    let frames: AvatarFrames = (Arc::clone(&PLACEHOLDERS[index]), vec![0]);
+    async move { Ok(frames) }.boxed()
+}
+
+/// scale frames down to fit in `max_size`, never scale up
+pub fn thumbnail(images: &Vec<Image>, max_size: i32) -> Result<Vec<Image>, Error> {
+    images.par_iter().map(|image| {
+        let scale = f32::min(
+            1.0,
+            max_size as f32 / i32::max(image.width(), image.height()) as f32,
+        );
+        if scale >= 1.0 {
+            return Ok(image.clone());
+        }
+        let width = i32::max(1, (image.width() as f32 * scale).round() as i32);
+        let height = i32::max(1, (image.height() as f32 * scale).round() as i32);
+        let mut surface = skia_safe::surfaces::raster_n32_premul((width, height))
+            .ok_or_else(|| ImageSynthesisError("Can not create thumbnail surface".to_string()))?;
+        surface.canvas().draw_image_rect_with_sampling_options(
+            image,
+            None,
+            Rect::from_iwh(width, height),
+            SamplingOptions::new(FilterMode::Linear, MipmapMode::Linear),
+            &Paint::default(),
+        );
+        Ok(surface.image_snapshot())
+    }).collect()
+}
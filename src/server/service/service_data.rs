@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::core::encoder::encoder::EncodeOptions;
 use crate::core::http::template_data::AvatarDataURL;
 use crate::core::template::text_template::TextData;
 
@@ -9,4 +10,6 @@ pub struct PetpetServiceData {
     pub avatar: AvatarDataURL,
     #[serde(default = "TextData::default")]
     pub text: TextData,
+    #[serde(flatten)]
+    pub encode: EncodeOptions,
 }
\ No newline at end of file